@@ -0,0 +1,79 @@
+//! Host side cost of `Kendryte::load`, on a mock device so that USB does not
+//! drown it out. Run with `cargo bench`.
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kendryte_boot::{Kendryte, MockTransport, SRAM_RUN_BASE};
+
+const OUT_EP: u8 = 0x01;
+const IN_EP: u8 = 0x81;
+
+/// About as much as fits in SRAM
+const LEN: usize = 1 << 20;
+
+fn kendryte(chunk_size: usize, latency: Duration) -> Kendryte<MockTransport> {
+    let mock = MockTransport::new().with_latency(latency).discarding();
+    Kendryte::new(mock, OUT_EP, IN_EP).with_chunk_size(chunk_size)
+}
+
+/// Everything but the transfers themselves, per chunk size
+fn chunk_overhead(c: &mut Criterion) {
+    let data = vec![0x5a_u8; LEN];
+    let mut group = c.benchmark_group("load");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for chunk_size in [512, 4096, 65536] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(chunk_size),
+            &chunk_size,
+            |b, &chunk_size| {
+                b.iter_batched(
+                    || kendryte(chunk_size, Duration::ZERO),
+                    |k| k.load(SRAM_RUN_BASE, &data[..]).unwrap(),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Hashing the data on the way, like `--manifest` and `checksum` do
+fn hashed(c: &mut Criterion) {
+    let data = vec![0x5a_u8; LEN];
+    let mut group = c.benchmark_group("load_crc32");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.bench_function("512", |b| {
+        b.iter_batched(
+            || (kendryte(512, Duration::ZERO), crc32fast::Hasher::new()),
+            |(k, mut crc)| {
+                k.load_with_progress(SRAM_RUN_BASE, &data[..], None, |d| crc.update(d))
+                    .unwrap();
+                crc.finalize()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+/// With each transfer taking about as long as a full speed one of 512 bytes,
+/// what the host adds on top should be lost in the noise.
+fn with_latency(c: &mut Criterion) {
+    const LEN: usize = 64 << 10;
+    let data = vec![0x5a_u8; LEN];
+    let mut group = c.benchmark_group("load_latency");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.sample_size(10);
+    group.bench_function("512", |b| {
+        b.iter_batched(
+            || kendryte(512, Duration::from_micros(350)),
+            |k| k.load(SRAM_RUN_BASE, &data[..]).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, chunk_overhead, hashed, with_latency);
+criterion_main!(benches);
@@ -0,0 +1,151 @@
+//! Checksums and digests of files, for the `checksum` command
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Read};
+
+// Both crates re-export the same trait
+#[cfg(feature = "md5")]
+use md5::{Digest, Md5};
+#[cfg(all(feature = "sha256", not(feature = "md5")))]
+use sha2::Digest;
+#[cfg(feature = "sha256")]
+use sha2::Sha256;
+
+use crate::cli::ChecksumAlgo;
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, starting from 0xffff
+fn crc16_ccitt(mut crc: u16, data: &[u8]) -> u16 {
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => crc << 1 ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Crc16(u16),
+    #[cfg(feature = "md5")]
+    Md5(Md5),
+    #[cfg(feature = "sha256")]
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algo: ChecksumAlgo) -> Self {
+        match algo {
+            ChecksumAlgo::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            ChecksumAlgo::Crc16Ccitt => Hasher::Crc16(0xffff),
+            #[cfg(feature = "md5")]
+            ChecksumAlgo::Md5 => Hasher::Md5(Md5::new()),
+            #[cfg(feature = "sha256")]
+            ChecksumAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(h) => h.update(data),
+            Hasher::Crc16(crc) => *crc = crc16_ccitt(*crc, data),
+            #[cfg(feature = "md5")]
+            Hasher::Md5(h) => h.update(data),
+            #[cfg(feature = "sha256")]
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    /// The result as hex, zero-padded to its full width
+    fn finish(self) -> String {
+        match self {
+            Hasher::Crc32(h) => format!("{:08x}", h.finalize()),
+            Hasher::Crc16(crc) => format!("{crc:04x}"),
+            #[cfg(feature = "md5")]
+            Hasher::Md5(h) => hex(&h.finalize()),
+            #[cfg(feature = "sha256")]
+            Hasher::Sha256(h) => hex(&h.finalize()),
+        }
+    }
+}
+
+#[cfg(any(feature = "md5", feature = "sha256"))]
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// SHA-256 of data as it is loaded, for `--manifest`; always None when
+/// built without the `sha256` feature
+pub struct InputDigest(RefCell<Option<Hasher>>);
+
+impl InputDigest {
+    pub fn new() -> Self {
+        Self(RefCell::new(Self::hasher()))
+    }
+
+    fn hasher() -> Option<Hasher> {
+        #[cfg(feature = "sha256")]
+        return Some(Hasher::new(ChecksumAlgo::Sha256));
+        #[cfg(not(feature = "sha256"))]
+        None
+    }
+
+    /// Start over, e.g. for the next copy of the same file.
+    pub fn reset(&self) {
+        *self.0.borrow_mut() = Self::hasher();
+    }
+
+    pub fn update(&self, data: &[u8]) {
+        if let Some(h) = self.0.borrow_mut().as_mut() {
+            h.update(data);
+        }
+    }
+
+    /// The digest as hex, and start over.
+    pub fn finish(&self) -> Option<String> {
+        let digest = self.0.take().map(Hasher::finish);
+        self.reset();
+        digest
+    }
+}
+
+/// Passes on what it reads to an [`InputDigest`], if there is one
+pub struct Digesting<'a, R> {
+    inner: R,
+    digest: Option<&'a InputDigest>,
+}
+
+impl<'a, R> Digesting<'a, R> {
+    pub fn new(inner: R, digest: Option<&'a InputDigest>) -> Self {
+        Self { inner, digest }
+    }
+}
+
+impl<R: Read> Read for Digesting<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(d) = self.digest {
+            d.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Compute `algo` over the contents of `file_name`, as hex string.
+pub fn file(file_name: &str, algo: ChecksumAlgo) -> io::Result<String> {
+    let mut reader = io::BufReader::new(File::open(file_name)?);
+    let mut hasher = Hasher::new(algo);
+    let mut buf = [0_u8; kendryte_boot::CHUNK_SIZE];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(hasher.finish())
+}
@@ -0,0 +1,634 @@
+//! Command line arguments
+
+use std::ffi::OsString;
+use std::fs;
+use std::str::FromStr;
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use kendryte_boot::{Chip, CLAIM_INTERFACE_PERIOD, CLAIM_INTERFACE_TIMEOUT};
+
+pub const SRAM_RUN_BASE: &str = "0x80360000";
+pub const DRAM_BASE: &str = "0x0";
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// List connected devices
+    #[clap(verbatim_doc_comment)]
+    Devices,
+    /// Check that the device is there and its interface can be claimed,
+    /// without sending it any requests; exits nonzero if not
+    #[clap(verbatim_doc_comment)]
+    Probe,
+    /// Print the configurations, interfaces and endpoints of the device,
+    /// e.g. to find what to pass to --out-ep and --in-ep
+    #[clap(verbatim_doc_comment)]
+    Describe,
+    /// Print the CRC32 or another checksum of a file, without talking to a
+    /// device
+    #[clap(verbatim_doc_comment)]
+    Checksum {
+        file_name: String,
+        #[clap(long, value_enum, default_value = "crc32")]
+        algo: ChecksumAlgo,
+    },
+    /// Print a completion script for the shell, e.g. to source from .bashrc
+    #[clap(verbatim_doc_comment)]
+    Completions { shell: Shell },
+    /// Print what comes in on a serial port, without talking to a device
+    #[clap(verbatim_doc_comment)]
+    Monitor {
+        #[clap(long)]
+        port: String,
+        #[clap(long, default_value_t = 115200)]
+        baud: u32,
+        /// Prefix each line with the seconds since the start
+        #[clap(long)]
+        timestamps: bool,
+        /// Pass on bytes as they come instead of whole lines
+        #[clap(long)]
+        raw: bool,
+        /// Also write everything to this file
+        #[clap(long)]
+        log: Option<String>,
+    },
+    /// Print CPU info
+    #[clap(verbatim_doc_comment)]
+    CpuInfo {
+        /// Print device and CPU info as JSON
+        #[clap(long)]
+        json: bool,
+        /// Fail unless the CPU info contains this, e.g. to check the chip
+        /// on a fixture before flashing
+        #[clap(long, visible_alias = "verify-info")]
+        expect: Option<String>,
+        /// Print the reply as text and hex instead of decoding it
+        #[clap(long)]
+        raw: bool,
+    },
+    /// Jump back to mask ROM
+    #[clap(verbatim_doc_comment)]
+    Rom,
+    /// Load binary, ELF, Intel HEX or SREC file to memory
+    #[clap(verbatim_doc_comment)]
+    Load {
+        #[clap(long, short, value_parser = parse_address, default_value = SRAM_RUN_BASE)]
+        address: u32,
+        /// File to load, or - to read from stdin
+        file_name: String,
+        /// Read the data back and compare it to the file
+        #[clap(long)]
+        verify: bool,
+        /// Load even to addresses that look wrong
+        #[clap(long)]
+        force: bool,
+        /// Bytes to skip at the start of the file; the first byte sent goes
+        /// to --address, and images are then loaded as raw data
+        #[clap(long, value_parser=clap_num::maybe_hex::<u64>, default_value_t = 0)]
+        skip: u64,
+        /// Bytes to send after the skipped ones instead of all up to the end
+        /// of the file; images are then loaded as raw data, too
+        #[clap(long, value_parser=clap_num::maybe_hex::<u64>)]
+        length: Option<u64>,
+        /// Pad the data to a multiple of this many bytes, with --pad-byte
+        #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+        align: u64,
+        /// Pad raw data to exactly this many bytes, before --align, and fail
+        /// if there are more
+        #[clap(long, value_parser=clap_num::maybe_hex::<u64>)]
+        pad_to: Option<u64>,
+        /// Byte to pad with for --pad-to and --align, instead of zero
+        #[clap(long, value_parser=clap_num::maybe_hex::<u8>, default_value_t = 0)]
+        pad_byte: u8,
+        /// Send the data length as 32-bit little-endian word after the data
+        #[clap(long)]
+        append_length: bool,
+        /// Send the CRC32 of the data as 32-bit little-endian word after it,
+        /// and after the length with --append-length
+        #[clap(long)]
+        append_crc32: bool,
+        /// Load this many times and report how many went through
+        #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        repeat: u32,
+        /// Skip chunks that keep failing instead of giving up, and list the
+        /// address ranges that were not loaded at the end
+        #[clap(long, conflicts_with = "verify")]
+        best_effort: bool,
+        /// Read each chunk back right after sending it and stop at the first
+        /// difference; about twice as slow
+        #[clap(long)]
+        verify_chunk: bool,
+        /// Load the file this many times, each --address-stride bytes after
+        /// the last, e.g. to redundant boot slots; for raw data
+        #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), requires = "address_stride")]
+        repeat_count: u32,
+        /// Distance between the copies of --repeat-count
+        #[clap(long, value_parser=clap_num::maybe_hex::<u32>, requires = "repeat_count")]
+        address_stride: Option<u32>,
+        /// Write what was loaded where to this file as JSON: file, SHA-256,
+        /// bytes sent, time, device serial number and the outcome
+        #[clap(long)]
+        manifest: Option<String>,
+    },
+    /// Run binary code, ELF, Intel HEX or SREC file
+    #[clap(verbatim_doc_comment)]
+    Run {
+        #[clap(long, short, value_parser = parse_address, default_value = SRAM_RUN_BASE)]
+        address: u32,
+        /// File to load, or - to read from stdin
+        file_name: String,
+        /// Read the data back and compare it to the file
+        #[clap(long)]
+        verify: bool,
+        /// Load even to addresses that look wrong
+        #[clap(long)]
+        force: bool,
+        /// Where to jump instead of the entry point of the image, or
+        /// --address: an address, or the name of a symbol in an ELF file
+        #[clap(long, value_parser = parse_entry)]
+        entry: Option<Entry>,
+        /// Milliseconds to wait after loading and flushing the caches before
+        /// the jump, for code that is sensitive to the timing
+        #[clap(long, default_value_t = 0)]
+        start_delay: u64,
+        /// After the jump, print what the code sends on the bulk IN endpoint
+        /// for up to <DRAIN_MS> ms, until the device goes away
+        #[clap(long)]
+        drain_ms: Option<u64>,
+        /// Serial port to print the output of the code from, until Ctrl-C
+        #[clap(long)]
+        monitor: Option<String>,
+        /// Report when the device drops off the bus after the jump, and
+        /// when it comes back, waiting at most <WATCH_DISCONNECT> ms for each
+        #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "5000", conflicts_with = "monitor")]
+        watch_disconnect: Option<u64>,
+        /// Baud rate for --monitor
+        #[clap(long, default_value_t = 115200, requires = "monitor")]
+        baud: u32,
+        /// Load and run this many times and report how many went through;
+        /// the code has to bring the device back to the USB loader each time
+        #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "monitor")]
+        repeat: u32,
+        /// Write what was loaded where to this file as JSON, like for `load`
+        #[clap(long)]
+        manifest: Option<String>,
+    },
+    /// Load several files to their addresses over one connection, then
+    /// optionally run one of them.
+    #[clap(verbatim_doc_comment)]
+    LoadMulti {
+        /// Address and file to load there, e.g. 0x80300000=spl.bin
+        #[clap(long = "image", value_name = "ADDR=PATH", value_parser = parse_placement, required = true)]
+        images: Vec<Placement>,
+        /// Flush caches and jump here after loading
+        #[clap(long, value_parser = parse_address)]
+        run: Option<u32>,
+        /// Read the data back and compare it to the files
+        #[clap(long)]
+        verify: bool,
+        /// Load even to addresses that look wrong
+        #[clap(long)]
+        force: bool,
+    },
+    /// Load and run an SPL, wait for it to come up as USB loader again after
+    /// setting up DRAM, then load and run the payload.
+    #[clap(verbatim_doc_comment)]
+    Boot {
+        /// First stage, loaded to SRAM
+        #[clap(long)]
+        spl: String,
+        #[clap(long, value_parser = parse_address, default_value = SRAM_RUN_BASE)]
+        spl_address: u32,
+        /// Second stage, loaded to DRAM
+        #[clap(long)]
+        payload: String,
+        #[clap(long, visible_alias = "dram-addr", value_parser = parse_address, default_value = DRAM_BASE)]
+        dram_address: u32,
+        /// How long to give the SPL before looking for it in milliseconds
+        #[clap(long, default_value_t = 500)]
+        delay: u64,
+        /// How long to wait for the SPL to show up in milliseconds
+        #[clap(long, default_value_t = 5000)]
+        reconnect_timeout: u64,
+    },
+    /// Stay around and load and run a file on every board that connects,
+    /// one after the other, until Ctrl-C
+    #[clap(verbatim_doc_comment)]
+    Watch {
+        /// File to load and run on each board
+        #[clap(long = "run")]
+        file_name: String,
+        #[clap(long, short, value_parser = parse_address, default_value = SRAM_RUN_BASE)]
+        address: u32,
+        /// Read the data back and compare it to the file
+        #[clap(long)]
+        verify: bool,
+        /// Load even to addresses that look wrong
+        #[clap(long)]
+        force: bool,
+    },
+    /// Jump back to mask ROM and wait for the device to reconnect
+    #[clap(verbatim_doc_comment)]
+    Reset {
+        /// How long to wait for the device in milliseconds
+        #[clap(long, default_value_t = 5000)]
+        reconnect_timeout: u64,
+    },
+    /// Flush the device caches
+    #[clap(verbatim_doc_comment)]
+    Flush,
+    /// Dump memory to file, or as hex to stdout
+    #[clap(verbatim_doc_comment)]
+    Dump {
+        #[clap(long, short, value_parser = parse_address, default_value = SRAM_RUN_BASE)]
+        address: u32,
+        #[clap(value_parser=clap_num::maybe_hex::<u32>)]
+        length: u32,
+        file_name: Option<String>,
+        /// How to write the data, by default binary to a file and hex to stdout
+        #[clap(long, value_enum)]
+        format: Option<DumpFormat>,
+        /// Write binary data, same as --format binary
+        #[clap(long, conflicts_with = "format")]
+        raw: bool,
+        /// Group hex output into words of this many bits
+        #[clap(long, value_enum)]
+        words: Option<Width>,
+        /// Byte order of the --words
+        #[clap(long, value_enum, default_value = "le", requires = "words")]
+        endian: Endian,
+    },
+    /// Fill memory with a byte value or a repeating pattern
+    #[clap(verbatim_doc_comment)]
+    Fill {
+        #[clap(long, short, value_parser = parse_address, default_value = SRAM_RUN_BASE)]
+        address: u32,
+        #[clap(long, short, value_parser=clap_num::maybe_hex::<u32>)]
+        length: u32,
+        /// Byte to fill with
+        #[clap(long, value_parser=clap_num::maybe_hex::<u8>, required_unless_present = "pattern")]
+        value: Option<u8>,
+        /// Bytes to repeat as hex string, e.g. deadbeef
+        #[clap(long, value_parser = parse_pattern, conflicts_with = "value")]
+        pattern: Option<Pattern>,
+    },
+    /// Load a known pattern to SRAM, read it back and compare, to check that
+    /// the cable, board and tool work without a firmware image
+    #[clap(verbatim_doc_comment)]
+    Selftest {
+        /// Where to write the pattern, by default scratch SRAM of the chip
+        #[clap(long, short, value_parser = parse_address)]
+        address: Option<u32>,
+        #[clap(long, short, value_parser=clap_num::maybe_hex::<u32>, default_value = "0x1000")]
+        length: u32,
+    },
+    /// Open the device once and read commands from stdin, one per line:
+    ///   load <file> [address]  load a file, to the SRAM base by default
+    ///   peek <address>         read a 32-bit word
+    ///   poke <address> <value> write a 32-bit word
+    ///   run [address]          flush caches and jump to the address
+    ///   info                   print the CPU info
+    ///   quit                   leave, same as end of input
+    #[clap(verbatim_doc_comment)]
+    Interactive,
+    /// Read a single word from memory
+    #[clap(verbatim_doc_comment)]
+    Peek {
+        #[clap(value_parser = parse_address)]
+        address: u32,
+        #[clap(long, value_enum, default_value = "32")]
+        width: Width,
+        #[clap(long, value_enum, default_value = "le")]
+        endian: Endian,
+    },
+    /// Write a single word to memory
+    #[clap(verbatim_doc_comment)]
+    Poke {
+        #[clap(value_parser = parse_address)]
+        address: u32,
+        #[clap(value_parser=clap_num::maybe_hex::<u32>)]
+        value: u32,
+        #[clap(long, value_enum, default_value = "32")]
+        width: Width,
+        #[clap(long, value_enum, default_value = "le")]
+        endian: Endian,
+    },
+    /// Send a vendor control request to the device, for trying out requests;
+    /// the replies to IN requests are printed as hex.
+    /// E.g. SET_DATA_ADDRESS 0x80360000 is --request 0x1 --value 0x8036.
+    #[clap(verbatim_doc_comment)]
+    Ctrl {
+        #[clap(long, value_parser=clap_num::maybe_hex::<u8>)]
+        request: u8,
+        #[clap(long, value_parser=clap_num::maybe_hex::<u16>, default_value_t = 0)]
+        value: u16,
+        #[clap(long, value_parser=clap_num::maybe_hex::<u16>, default_value_t = 0)]
+        index: u16,
+        /// Send the request without data, which is the default
+        #[clap(long, conflicts_with = "input")]
+        out: bool,
+        /// Read the reply to the request
+        #[clap(long = "in")]
+        input: bool,
+        /// Bytes to read with --in
+        #[clap(long, value_parser=clap_num::maybe_hex::<u16>, default_value_t = 64, requires = "input")]
+        length: u16,
+    },
+}
+
+/// Access size in bits
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Width {
+    #[value(name = "8")]
+    W8,
+    #[value(name = "16")]
+    W16,
+    #[value(name = "32")]
+    W32,
+}
+
+impl Width {
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::W8 => 1,
+            Width::W16 => 2,
+            Width::W32 => 4,
+        }
+    }
+}
+
+/// Output formats of `dump`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    /// Address, hex bytes and ASCII, like xxd
+    Hex,
+    /// A C array initializer
+    CArray,
+    /// The bytes as they are
+    Binary,
+}
+
+/// What `checksum` computes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumAlgo {
+    Crc32,
+    /// CRC-16/CCITT-FALSE
+    Crc16Ccitt,
+    #[cfg(feature = "md5")]
+    Md5,
+    #[cfg(feature = "sha256")]
+    Sha256,
+}
+
+/// How to report the outcome of a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Only the log on stderr
+    Text,
+    /// A JSON object on stdout at the end, see `CommandResult`
+    Json,
+}
+
+/// Byte order of words in memory; RISC-V is little-endian
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Endian {
+    Le,
+    Be,
+}
+
+impl Endian {
+    pub fn encode(self, value: u32, width: Width) -> Vec<u8> {
+        let n = width.bytes();
+        match self {
+            Endian::Le => value.to_le_bytes()[..n].to_vec(),
+            Endian::Be => value.to_be_bytes()[4 - n..].to_vec(),
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> u32 {
+        let fold = |v: u32, b: &u8| v << 8 | *b as u32;
+        match self {
+            Endian::Le => bytes.iter().rev().fold(0, fold),
+            Endian::Be => bytes.iter().fold(0, fold),
+        }
+    }
+}
+
+/// Bytes to fill memory with
+#[derive(Clone, Debug)]
+pub struct Pattern(pub Vec<u8>);
+
+pub fn parse_pattern(s: &str) -> std::result::Result<Pattern, String> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    let hex = s.bytes().all(|b| b.is_ascii_hexdigit());
+    if !hex || s.is_empty() || !s.len().is_multiple_of(2) {
+        return Err("expected an even number of hex digits".into());
+    }
+    let bytes = (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect();
+    Ok(Pattern(bytes))
+}
+
+/// A file to load and where to, from `ADDR=PATH`
+#[derive(Clone, Debug)]
+pub struct Placement {
+    pub address: u32,
+    pub file_name: String,
+}
+
+pub fn parse_placement(s: &str) -> std::result::Result<Placement, String> {
+    let (address, file_name) = s
+        .split_once('=')
+        .ok_or("expected <ADDRESS>=<FILE>, e.g. 0x80300000=spl.bin")?;
+    Ok(Placement {
+        address: parse_address(address)?,
+        file_name: file_name.into(),
+    })
+}
+
+/// Parse an address given as number, as name of a place in memory like
+/// `sram`, or as name plus offset like `dram+0x1000`, see [`Chip::preset`].
+/// The names stand for the same addresses on all chips so far, so they do
+/// not depend on --chip.
+pub fn parse_address(s: &str) -> std::result::Result<u32, String> {
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        return clap_num::maybe_hex::<u32>(s);
+    }
+    let (name, offset) = match s.split_once('+') {
+        Some((name, offset)) => (name, clap_num::maybe_hex::<u32>(offset)?),
+        None => (s, 0),
+    };
+    let unknown = || {
+        let names = Chip::PRESETS.join(", ");
+        format!("unknown address '{name}', expected a number or one of: {names}")
+    };
+    let mut addrs = Chip::ALL.iter().map(|c| c.preset(name));
+    let base = addrs.next().flatten().ok_or_else(unknown)?;
+    if addrs.any(|a| a != Some(base)) {
+        return Err(format!("'{name}' differs between chips, give the address"));
+    }
+    base.checked_add(offset)
+        .ok_or_else(|| format!("'{s}' exceeds the 32-bit address space"))
+}
+
+/// Where to start running loaded code
+#[derive(Clone, Debug)]
+pub enum Entry {
+    Address(u32),
+    Symbol(String),
+}
+
+pub fn parse_entry(s: &str) -> std::result::Result<Entry, String> {
+    match s.starts_with(|c: char| c.is_ascii_digit()) {
+        true => clap_num::maybe_hex::<u32>(s).map(Entry::Address),
+        false => Ok(Entry::Symbol(s.into())),
+    }
+}
+
+/// Replace each `@<file>` argument with the arguments in the file, one per
+/// line so that they may contain spaces. Empty lines and lines starting with
+/// `#` are skipped.
+pub fn expand_arg_files(
+    args: impl IntoIterator<Item = OsString>,
+) -> std::result::Result<Vec<OsString>, String> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        let Some(path) = arg.to_str().and_then(|a| a.strip_prefix('@')) else {
+            expanded.push(arg);
+            continue;
+        };
+        let text = fs::read_to_string(path).map_err(|e| format!("'@{path}': {e}"))?;
+        let lines = text.lines().map(str::trim);
+        expanded.extend(
+            lines
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(OsString::from),
+        );
+    }
+    Ok(expanded)
+}
+
+/// Kendryte mask ROM loader tool
+#[derive(Parser, Debug)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Arguments can also be read from a file given as @<file>, one per line."
+)]
+pub struct Cli {
+    /// Command to run
+    #[command(subcommand)]
+    pub cmd: Command,
+    /// More output, repeat for even more
+    #[clap(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Only print errors, no log or progress, for scripts that go by the
+    /// exit code; twice to not even print errors
+    #[clap(short, long, action = ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+    /// Do not announce the payload size before loading (older firmware)
+    #[clap(long)]
+    pub no_data_length: bool,
+    /// Do not read the CPU info before running the command, which also
+    /// skips the mask ROM check before loading
+    #[clap(long)]
+    pub no_dev_info: bool,
+    /// Load even if the device does not look like the mask ROM
+    #[clap(long)]
+    pub no_rom_check: bool,
+    /// Chip to look for, default is to probe for all known chips
+    #[clap(long, value_parser = Chip::from_str)]
+    pub chip: Option<Chip>,
+    /// USB vendor ID to look for instead of the chip's
+    #[clap(long, env = "KENDRYTE_VID", value_parser=clap_num::maybe_hex::<u16>)]
+    pub vid: Option<u16>,
+    /// USB product ID to look for instead of the chip's
+    #[clap(long, env = "KENDRYTE_PID", value_parser=clap_num::maybe_hex::<u16>)]
+    pub pid: Option<u16>,
+    /// Serial number of the device to use when several are connected
+    #[clap(long)]
+    pub device: Option<String>,
+    /// Bus and port path of the device to use, e.g. 3-1.2, as listed by
+    /// `devices`
+    #[clap(long)]
+    pub bus_port: Option<String>,
+    /// Use the nth of the matching devices, counting from 0 in the order
+    /// `devices` lists them, e.g. for boards without serial numbers
+    #[clap(long)]
+    pub index: Option<usize>,
+    /// Wait for the device to show up, optionally for at most <WAIT> ms
+    #[clap(long, num_args = 0..=1)]
+    pub wait: Option<Option<u64>>,
+    /// Look for the device at most this many more times while waiting for
+    /// it, after the first
+    #[clap(long)]
+    pub wait_retries: Option<u32>,
+    /// Milliseconds between looks for the device while waiting, doubling
+    /// each time up to 2 s
+    #[clap(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    pub wait_backoff_ms: u64,
+    /// Bytes per bulk transfer, default depends on the USB speed
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub chunk_size: Option<u32>,
+    /// Bulk transfers to keep in flight; failed ones are only retried at 1
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub depth: u32,
+    /// Print a JSON object with the outcome to stdout at the end
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+    /// Log the USB transfers instead of looking for a device and sending them
+    #[clap(long)]
+    pub dry_run: bool,
+    /// Timeout for each single USB transfer in milliseconds
+    ///
+    /// This bounds every control request and bulk chunk on its own, so a
+    /// large load is not limited by it as a whole. It only applies once the
+    /// USB interface has been claimed, see --claim-timeout. The default
+    /// grows with the chunk size and shrinks with the USB speed.
+    #[clap(long)]
+    pub timeout: Option<u64>,
+    /// Give up on the whole operation after this many seconds, counted from
+    /// when the device is first opened
+    #[clap(long)]
+    pub global_timeout: Option<u64>,
+    /// Address of the mask ROM to jump to for `rom` and `reset`, in case it
+    /// differs on a chip revision
+    #[clap(long, value_parser=clap_num::maybe_hex::<u32>)]
+    pub rom_base: Option<u32>,
+    /// USB interface to claim, default is the first one; see -v for a list
+    #[clap(long)]
+    pub interface: Option<u8>,
+    /// Alternate setting of the interface, default is the first one
+    #[clap(long)]
+    pub alt_setting: Option<u8>,
+    /// Bulk OUT endpoint to use, default is the first bulk OUT one
+    #[clap(long, value_parser=clap_num::maybe_hex::<u8>)]
+    pub out_ep: Option<u8>,
+    /// Bulk IN endpoint to use, default is the first bulk IN one
+    #[clap(long, value_parser=clap_num::maybe_hex::<u8>)]
+    pub in_ep: Option<u8>,
+    /// How long to keep trying to open the device and claim the USB
+    /// interface in milliseconds
+    #[clap(long, default_value_t = CLAIM_INTERFACE_TIMEOUT.as_millis() as u64)]
+    pub claim_timeout: u64,
+    /// How long to wait between attempts to claim the USB interface in
+    /// microseconds
+    #[clap(long, default_value_t = CLAIM_INTERFACE_PERIOD.as_micros() as u64)]
+    pub claim_period: u64,
+    /// Detach a kernel driver bound to the interface before claiming it
+    #[clap(long)]
+    pub detach_kernel_driver: bool,
+    /// Shell command to run before loading, e.g. to switch a power rail;
+    /// the load is aborted if it fails. KENDRYTE_SERIAL and KENDRYTE_PORT
+    /// are set to the serial number and port path of the device.
+    #[clap(long)]
+    pub pre_load_command: Option<String>,
+    /// Shell command to run after jumping to the loaded code, like
+    /// --pre-load-command
+    #[clap(long)]
+    pub post_run_command: Option<String>,
+}
@@ -0,0 +1,33 @@
+//! Compressed input files, decompressed while loading
+
+use std::io::{self, BufReader, Read};
+
+use flate2::read::MultiGzDecoder;
+
+/// How an input file is compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect compression from the first bytes of a file, or else its name.
+    pub fn detect(file_name: &str, head: &[u8]) -> Option<Compression> {
+        match head {
+            [0x1f, 0x8b, ..] => Some(Compression::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Compression::Zstd),
+            _ if file_name.ends_with(".gz") => Some(Compression::Gzip),
+            _ if file_name.ends_with(".zst") => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Wrap `reader` to read the decompressed data.
+    pub fn decoder<'a>(self, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::with_buffer(BufReader::new(reader))?),
+        })
+    }
+}
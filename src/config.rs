@@ -0,0 +1,91 @@
+//! Defaults from `kendryte_boot.toml`, overridden by command line flags
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use clap::Command;
+use kendryte_boot::Error;
+use serde::Deserialize;
+
+const FILE_NAME: &str = "kendryte_boot.toml";
+
+/// Everything that can be set in the config file, e.g.
+///
+/// ```toml
+/// address = 0x80360000
+/// timeout = 8000
+/// device = "ABC123"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Default for --address of all commands that have it
+    address: Option<u32>,
+    timeout: Option<u64>,
+    chip: Option<String>,
+    device: Option<String>,
+    chunk_size: Option<u32>,
+}
+
+/// Where to look for the config file, in order
+fn paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(FILE_NAME)];
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+    if let Some(dir) = config_home {
+        paths.push(dir.join(FILE_NAME));
+    }
+    paths
+}
+
+impl Config {
+    /// Read the first config file found, if any.
+    pub fn find() -> Result<Option<(PathBuf, Config)>, Error> {
+        let Some(path) = paths().into_iter().find(|p| p.is_file()) else {
+            return Ok(None);
+        };
+        let name = path.display().to_string();
+        let text = fs::read_to_string(&path).map_err(Error::file(&name))?;
+        let config = toml::from_str(&text).map_err(|e| Error::Config {
+            path: name,
+            message: e.message().into(),
+        })?;
+        Ok(Some((path, config)))
+    }
+
+    /// Make the settings the defaults of `cmd`'s arguments.
+    pub fn apply(&self, mut cmd: Command) -> Command {
+        let globals = [
+            ("timeout", self.timeout.map(|t| t.to_string())),
+            ("chip", self.chip.clone()),
+            ("device", self.device.clone()),
+            ("chunk_size", self.chunk_size.map(|c| c.to_string())),
+        ];
+        for (id, value) in globals {
+            if let Some(value) = value {
+                cmd = cmd.mut_arg(id, |a| a.default_value(value));
+            }
+        }
+
+        let Some(address) = self.address else {
+            return cmd;
+        };
+        // Only where the address is optional, not for e.g. peek.
+        let subcommands: Vec<String> = cmd
+            .get_subcommands()
+            .filter(|s| {
+                s.get_arguments()
+                    .any(|a| a.get_id() == "address" && !a.is_required_set())
+            })
+            .map(|s| s.get_name().into())
+            .collect();
+        for name in subcommands {
+            cmd = cmd.mut_subcommand(name, |s| {
+                s.mut_arg("address", |a| a.default_value(format!("{address:#x}")))
+            });
+        }
+        cmd
+    }
+}
@@ -0,0 +1,170 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::{Cancelled, ChunkError};
+
+/// Everything that can go wrong between finding a device and running code
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot list USB devices: {0}")]
+    Enumerate(io::Error),
+    #[error("Device not found, is it connected and in the right mode?")]
+    DeviceNotFound,
+    #[error(
+        "Device is not in mask ROM mode, it says '{reply}' instead of '{expected}...'; \
+         hold the boot button while resetting it, or pass --no-rom-check"
+    )]
+    NotInRom { expected: String, reply: String },
+    #[error(
+        "Multiple devices found, select one with --device <serial>, --bus-port <path> \
+         or --index <n>"
+    )]
+    MultipleDevices,
+    #[error("Cannot open device: {0}")]
+    Open(io::Error),
+    #[error("Device has no {0}")]
+    Descriptor(&'static str),
+    #[error("Device has no interface {interface} with alternate setting {alt}")]
+    NoInterface { interface: u8, alt: u8 },
+    #[error("Cannot claim USB interface {interface}: {source}")]
+    Claim { interface: u8, source: io::Error },
+    #[error(
+        "USB interface {interface} is in use by a kernel driver or another program \
+         ({source}); stop the other program or pass --detach-kernel-driver"
+    )]
+    Busy { interface: u8, source: io::Error },
+    #[error(
+        "USB interface {interface} needs the WinUSB driver (device driver: {driver}): {source}; \
+         install it for the device, e.g. with Zadig (https://zadig.akeo.ie)"
+    )]
+    WinUsb {
+        interface: u8,
+        driver: String,
+        source: io::Error,
+    },
+    #[error("Cannot select alternate setting {alt} of interface {interface}: {source}")]
+    AltSetting {
+        interface: u8,
+        alt: u8,
+        source: io::Error,
+    },
+    #[error("No {direction} endpoint found, interface has: {found}")]
+    NoEndpoint {
+        direction: &'static str,
+        /// The endpoints that are there instead
+        found: String,
+    },
+    #[error("No endpoint {endpoint:#04x}, interface has: {found}")]
+    MissingEndpoint { endpoint: u8, found: String },
+    #[error("Endpoint {endpoint:#04x} is not an {direction} endpoint")]
+    EndpointDirection {
+        endpoint: u8,
+        direction: &'static str,
+    },
+    #[error("{path}: {source}")]
+    File { path: String, source: io::Error },
+    #[error("{path}: {source}")]
+    Image { path: String, source: io::Error },
+    #[error("{context}: {source}")]
+    Transfer { context: String, source: io::Error },
+    #[error(
+        "Refusing to {op} {len:#x} bytes at {addr:#010x}: {reason}{}",
+        if *op == "load" { " (use --force to load anyway)" } else { "" }
+    )]
+    InvalidRegion {
+        /// What was refused, e.g. `load` or `dump`
+        op: &'static str,
+        addr: u32,
+        len: u64,
+        reason: &'static str,
+    },
+    #[error("Data is {len:#x} bytes, more than --pad-to {max:#x}")]
+    TooLarge { len: u64, max: u64 },
+    #[error("{bytes:#x} bytes in {ranges} range(s) could not be loaded")]
+    ChunksFailed { ranges: usize, bytes: u64 },
+    #[error("{failed} of {total} iterations failed")]
+    Repeat { failed: u32, total: u32 },
+    #[error("Cannot run from symbol '{name}': {reason}")]
+    Symbol { name: String, reason: String },
+    #[error("Operation exceeded {secs} seconds: {source}")]
+    Deadline { secs: u64, source: Box<Error> },
+    #[error("CPU info does not match:\n- {expected}\n+ {reply}")]
+    InfoMismatch { expected: String, reply: String },
+    #[error("Verification failed: {0}")]
+    Verify(io::Error),
+    #[error("Hook '{command}' failed: {status}")]
+    Hook { command: String, status: String },
+    #[error("Serial port '{port}': {source}")]
+    Serial { port: String, source: io::Error },
+    #[error("{path}: {message}")]
+    Config { path: String, message: String },
+}
+
+impl Error {
+    /// Wrap a transfer error with what was being done, for `map_err`.
+    pub fn transfer(context: impl Into<String>) -> impl FnOnce(io::Error) -> Error {
+        let context = context.into();
+        move |source| Error::Transfer { context, source }
+    }
+
+    pub fn file(path: impl Into<String>) -> impl FnOnce(io::Error) -> Error {
+        let path = path.into();
+        move |source| Error::File { path, source }
+    }
+
+    /// A short name for what went wrong, e.g. `transfer_timeout`, that stays
+    /// the same between versions, for tools to tell errors apart
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Enumerate(_) => "enumerate",
+            Error::DeviceNotFound => "device_not_found",
+            Error::NotInRom { .. } => "not_in_rom",
+            Error::MultipleDevices => "multiple_devices",
+            Error::Open(_) => "open",
+            Error::Descriptor(_) => "descriptor",
+            Error::NoInterface { .. } => "no_interface",
+            Error::Claim { .. } => "claim",
+            Error::Busy { .. } => "busy",
+            Error::WinUsb { .. } => "winusb",
+            Error::AltSetting { .. } => "alt_setting",
+            Error::NoEndpoint { .. } => "no_endpoint",
+            Error::MissingEndpoint { .. } => "missing_endpoint",
+            Error::EndpointDirection { .. } => "endpoint_direction",
+            Error::File { .. } => "file",
+            Error::Image { .. } => "image",
+            Error::Transfer { source, .. } if source.kind() == io::ErrorKind::TimedOut => {
+                "transfer_timeout"
+            }
+            Error::Transfer { source, .. }
+                if source.get_ref().is_some_and(|e| e.is::<Cancelled>()) =>
+            {
+                "cancelled"
+            }
+            Error::Transfer { .. } => "transfer",
+            Error::InvalidRegion { .. } => "invalid_region",
+            Error::TooLarge { .. } => "too_large",
+            Error::ChunksFailed { .. } => "chunks_failed",
+            Error::Repeat { .. } => "repeat",
+            Error::Symbol { .. } => "symbol",
+            Error::Deadline { .. } => "deadline",
+            Error::InfoMismatch { .. } => "info_mismatch",
+            Error::Verify(_) => "verify",
+            Error::Hook { .. } => "hook",
+            Error::Serial { .. } => "serial",
+            Error::Config { .. } => "config",
+        }
+    }
+
+    /// Offset into the data where loading failed, if that is what failed
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Error::Transfer { source, .. } => {
+                let chunk = source.get_ref()?.downcast_ref::<ChunkError>()?;
+                Some(chunk.offset)
+            }
+            Error::Deadline { source, .. } => source.offset(),
+            _ => None,
+        }
+    }
+}
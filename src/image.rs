@@ -0,0 +1,101 @@
+//! Loadable images made of segments, parsed from structured file formats
+
+use std::io::{self, Result};
+
+mod elf;
+mod ihex;
+mod srec;
+
+/// A contiguous block of data to place at a device address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+/// A set of segments and an optional entry point
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u32>,
+}
+
+/// File formats that carry their own load addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Elf,
+    IntelHex,
+    Srec,
+}
+
+impl Format {
+    /// Detect the format from the first bytes of a file.
+    pub fn detect(head: &[u8]) -> Option<Format> {
+        if head.starts_with(elf::MAGIC) {
+            return Some(Format::Elf);
+        }
+        if head.starts_with(b":") {
+            return Some(Format::IntelHex);
+        }
+        if srec::is_srec(head) {
+            return Some(Format::Srec);
+        }
+        None
+    }
+}
+
+impl Image {
+    pub fn parse(format: Format, data: &[u8]) -> Result<Image> {
+        match format {
+            Format::Elf => elf::parse(data),
+            Format::IntelHex => ihex::parse(data),
+            Format::Srec => srec::parse(data),
+        }
+    }
+
+    /// Total number of bytes in all segments
+    pub fn len(&self) -> u64 {
+        self.segments.iter().map(|s| s.data.len() as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(|s| s.data.is_empty())
+    }
+}
+
+/// Look up the address symbol `name` is loaded to, in a file of `format`.
+/// Only ELF files have symbols.
+pub fn find_symbol(format: Format, data: &[u8], name: &str) -> Result<Option<u32>> {
+    match format {
+        Format::Elf => elf::symbol(data, name),
+        Format::IntelHex | Format::Srec => Ok(None),
+    }
+}
+
+/// Append `data` at `addr`, extending the last segment if contiguous.
+fn push_data(segments: &mut Vec<Segment>, addr: u32, data: &[u8]) {
+    if let Some(last) = segments.last_mut() {
+        if last.addr as u64 + last.data.len() as u64 == addr as u64 {
+            last.data.extend_from_slice(data);
+            return;
+        }
+    }
+    segments.push(Segment {
+        addr,
+        data: data.to_vec(),
+    });
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
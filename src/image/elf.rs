@@ -0,0 +1,231 @@
+use std::io::Result;
+
+use goblin::elf::{program_header::PT_LOAD, Elf};
+
+use super::{invalid, Image, Segment};
+
+pub const MAGIC: &[u8] = b"\x7fELF";
+
+fn to_u32(v: u64, what: &str) -> Result<u32> {
+    u32::try_from(v).map_err(|_| invalid(format!("{what} {v:#x} does not fit in 32 bits")))
+}
+
+/// Collect the PT_LOAD segments to place at their physical addresses.
+pub fn parse(data: &[u8]) -> Result<Image> {
+    let elf = Elf::parse(data).map_err(|e| invalid(format!("invalid ELF file: {e}")))?;
+
+    let mut segments = Vec::new();
+    for ph in elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        // Nothing to transfer for BSS, the loaded code clears it.
+        if ph.p_filesz == 0 {
+            continue;
+        }
+        let range = ph.file_range();
+        let bytes = data
+            .get(range.clone())
+            .ok_or_else(|| invalid(format!("segment at {:#x} exceeds file", range.start)))?;
+        segments.push(Segment {
+            addr: to_u32(ph.p_paddr, "segment address")?,
+            data: bytes.to_vec(),
+        });
+    }
+
+    let entry = Some(to_u32(physical(&elf, elf.entry)?, "entry point")?);
+    Ok(Image { segments, entry })
+}
+
+/// The physical address that virtual address `vaddr` is loaded to, going by
+/// the PT_LOAD segment it is in, or `vaddr` itself if it is in none.
+fn physical(elf: &Elf, vaddr: u64) -> Result<u64> {
+    for ph in elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        let overflow = || invalid(format!("segment at {:#x} overflows", ph.p_vaddr));
+        let end = ph.p_vaddr.checked_add(ph.p_memsz).ok_or_else(overflow)?;
+        if (ph.p_vaddr..end).contains(&vaddr) {
+            return ph
+                .p_paddr
+                .checked_add(vaddr - ph.p_vaddr)
+                .ok_or_else(overflow);
+        }
+    }
+    Ok(vaddr)
+}
+
+/// Find symbol `name` and return the physical address it is loaded to.
+pub fn symbol(data: &[u8], name: &str) -> Result<Option<u32>> {
+    let elf = Elf::parse(data).map_err(|e| invalid(format!("invalid ELF file: {e}")))?;
+    let Some(sym) = elf
+        .syms
+        .iter()
+        .find(|s| elf.strtab.get_at(s.st_name) == Some(name))
+    else {
+        return Ok(None);
+    };
+    // Symbols have virtual addresses, segments are loaded to physical ones.
+    let paddr = physical(&elf, sym.st_value)?;
+    Ok(Some(to_u32(paddr, "symbol address")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::elf::program_header::PT_NOTE;
+    use goblin::elf::section_header::{SHT_STRTAB, SHT_SYMTAB};
+
+    const EHDR_SIZE: usize = 64;
+    const PHDR_SIZE: usize = 56;
+    const SHDR_SIZE: usize = 64;
+
+    /// A program header with its file contents
+    struct Ph {
+        p_type: u32,
+        vaddr: u64,
+        paddr: u64,
+        data: &'static [u8],
+        memsz: u64,
+    }
+
+    fn load(vaddr: u64, paddr: u64, data: &'static [u8], memsz: u64) -> Ph {
+        Ph {
+            p_type: PT_LOAD,
+            vaddr,
+            paddr,
+            data,
+            memsz,
+        }
+    }
+
+    /// A little endian ELF64 file with the segments and a symbol table
+    fn elf(entry: u64, phs: &[Ph], syms: &[(&str, u64)]) -> Vec<u8> {
+        let mut contents = Vec::new();
+        let data_start = EHDR_SIZE + PHDR_SIZE * phs.len();
+        let mut phdrs = Vec::new();
+        for ph in phs {
+            let offset = (data_start + contents.len()) as u64;
+            contents.extend_from_slice(ph.data);
+            phdrs.extend_from_slice(&ph.p_type.to_le_bytes());
+            phdrs.extend_from_slice(&7_u32.to_le_bytes());
+            for v in [
+                offset,
+                ph.vaddr,
+                ph.paddr,
+                ph.data.len() as u64,
+                ph.memsz,
+                8,
+            ] {
+                phdrs.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 24];
+        for (name, value) in syms {
+            symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&[0x10, 0, 1, 0]);
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&0_u64.to_le_bytes());
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let strtab_offset = data_start + contents.len();
+        let symtab_offset = strtab_offset + strtab.len();
+        let shoff = symtab_offset + symtab.len();
+
+        let mut shdrs = vec![0; SHDR_SIZE];
+        let sections = [
+            (SHT_STRTAB, strtab_offset, strtab.len(), 0, 0),
+            (SHT_SYMTAB, symtab_offset, symtab.len(), 1, 24),
+        ];
+        for (sh_type, offset, size, link, entsize) in sections {
+            shdrs.extend_from_slice(&0_u32.to_le_bytes());
+            shdrs.extend_from_slice(&sh_type.to_le_bytes());
+            for v in [0, 0, offset as u64, size as u64] {
+                shdrs.extend_from_slice(&v.to_le_bytes());
+            }
+            shdrs.extend_from_slice(&(link as u32).to_le_bytes());
+            shdrs.extend_from_slice(&1_u32.to_le_bytes());
+            shdrs.extend_from_slice(&8_u64.to_le_bytes());
+            shdrs.extend_from_slice(&(entsize as u64).to_le_bytes());
+        }
+
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&[2, 1, 1]);
+        file.resize(16, 0);
+        file.extend_from_slice(&2_u16.to_le_bytes());
+        file.extend_from_slice(&0xf3_u16.to_le_bytes());
+        file.extend_from_slice(&1_u32.to_le_bytes());
+        for v in [entry, EHDR_SIZE as u64, shoff as u64] {
+            file.extend_from_slice(&v.to_le_bytes());
+        }
+        file.extend_from_slice(&0_u32.to_le_bytes());
+        for v in [EHDR_SIZE, PHDR_SIZE, phs.len(), SHDR_SIZE, 3, 0] {
+            file.extend_from_slice(&(v as u16).to_le_bytes());
+        }
+        for part in [phdrs, contents, strtab, symtab, shdrs] {
+            file.extend_from_slice(&part);
+        }
+        file
+    }
+
+    fn test_elf() -> Vec<u8> {
+        let phs = [
+            load(0x1000_0000, 0x8036_0000, b"codecode", 8),
+            // Initialized data with BSS after it
+            load(0x1000_1000, 0x8036_1000, b"data", 0x100),
+            // Only BSS
+            load(0x1000_2000, 0x8036_2000, b"", 0x40),
+            Ph {
+                p_type: PT_NOTE,
+                vaddr: 0,
+                paddr: 0,
+                data: b"note",
+                memsz: 4,
+            },
+        ];
+        let syms = [("_start", 0x1000_0000), ("bss_end", 0x1000_1080)];
+        elf(0x1000_0004, &phs, &syms)
+    }
+
+    #[test]
+    fn only_file_contents_of_load_segments_are_sent() {
+        let image = parse(&test_elf()).unwrap();
+        let segments = [
+            Segment {
+                addr: 0x8036_0000,
+                data: b"codecode".to_vec(),
+            },
+            // The BSS is left for the loaded code to clear.
+            Segment {
+                addr: 0x8036_1000,
+                data: b"data".to_vec(),
+            },
+        ];
+        assert_eq!(image.segments, segments);
+    }
+
+    #[test]
+    fn entry_is_at_its_physical_address() {
+        let image = parse(&test_elf()).unwrap();
+        assert_eq!(image.entry, Some(0x8036_0004));
+
+        // Outside of all segments, it is taken as it is.
+        let phs = [load(0x1000_0000, 0x8036_0000, b"code", 4)];
+        let image = parse(&elf(0x8036_0000, &phs, &[])).unwrap();
+        assert_eq!(image.entry, Some(0x8036_0000));
+    }
+
+    #[test]
+    fn symbols_are_at_their_physical_address() {
+        let data = test_elf();
+        assert_eq!(symbol(&data, "_start").unwrap(), Some(0x8036_0000));
+        assert_eq!(symbol(&data, "bss_end").unwrap(), Some(0x8036_1080));
+        assert_eq!(symbol(&data, "main").unwrap(), None);
+    }
+
+    #[test]
+    fn overflowing_segment_is_an_error() {
+        let phs = [load(u64::MAX - 0x10, 0, b"", 0x100)];
+        let data = elf(0, &phs, &[("_start", 0x1000)]);
+        let e = symbol(&data, "_start").unwrap_err();
+        assert!(e.to_string().contains("overflows"), "{e}");
+    }
+}
@@ -0,0 +1,118 @@
+use std::io::Result;
+
+use super::{decode_hex, invalid, push_data, Image};
+
+const DATA: u8 = 0x00;
+const EOF: u8 = 0x01;
+const EXT_SEGMENT_ADDR: u8 = 0x02;
+const START_SEGMENT_ADDR: u8 = 0x03;
+const EXT_LINEAR_ADDR: u8 = 0x04;
+const START_LINEAR_ADDR: u8 = 0x05;
+
+fn be(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |v, b| (v << 8) | *b as u32)
+}
+
+pub fn parse(data: &[u8]) -> Result<Image> {
+    let text = std::str::from_utf8(data).map_err(|_| invalid("HEX file is not text"))?;
+
+    let mut image = Image::default();
+    let mut base = 0_u32;
+    let mut eof = false;
+    for (n, line) in text.lines().enumerate() {
+        let n = n + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| invalid(format!("line {n}: {msg}"));
+
+        let rec = line
+            .strip_prefix(':')
+            .ok_or_else(|| err("record does not start with ':'"))?;
+        let bytes = decode_hex(rec).ok_or_else(|| err("invalid hex digits"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(err("record length mismatch"));
+        }
+        let sum = bytes.iter().fold(0_u8, |s, b| s.wrapping_add(*b));
+        if sum != 0 {
+            return Err(err("checksum mismatch"));
+        }
+
+        let offset = be(&bytes[1..3]);
+        let payload = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            DATA => {
+                let addr = base
+                    .checked_add(offset)
+                    .ok_or_else(|| err("address overflow"))?;
+                push_data(&mut image.segments, addr, payload);
+            }
+            EOF => {
+                eof = true;
+                break;
+            }
+            EXT_SEGMENT_ADDR if payload.len() == 2 => base = be(payload) << 4,
+            START_SEGMENT_ADDR if payload.len() == 4 => {
+                image.entry = Some((be(&payload[..2]) << 4) + be(&payload[2..]));
+            }
+            EXT_LINEAR_ADDR if payload.len() == 2 => base = be(payload) << 16,
+            START_LINEAR_ADDR if payload.len() == 4 => image.entry = Some(be(payload)),
+            EXT_SEGMENT_ADDR..=START_LINEAR_ADDR => return Err(err("invalid record length")),
+            t => return Err(err(&format!("unknown record type {t:02x}"))),
+        }
+    }
+    // Without it, the file may have been cut short.
+    if !eof {
+        return Err(invalid("HEX file has no end of file record"));
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Segment;
+
+    fn parse_lines(lines: &[&str]) -> Result<Image> {
+        parse(lines.join("\n").as_bytes())
+    }
+
+    #[test]
+    fn extended_linear_address_and_start_linear_address() {
+        let image = parse_lines(&[
+            ":02000004803644",
+            ":0400100001020304E2",
+            ":040000058036001031",
+            ":00000001FF",
+        ])
+        .unwrap();
+        let segment = Segment {
+            addr: 0x8036_0010,
+            data: vec![1, 2, 3, 4],
+        };
+        assert_eq!(image.segments, [segment]);
+        assert_eq!(image.entry, Some(0x8036_0010));
+    }
+
+    #[test]
+    fn extended_segment_address() {
+        let image = parse_lines(&[":020000021000EC", ":02000000AABB99", ":00000001FF"]).unwrap();
+        assert_eq!(image.segments[0].addr, 0x1_0000);
+        assert_eq!(image.segments[0].data, [0xaa, 0xbb]);
+        assert_eq!(image.entry, None);
+    }
+
+    #[test]
+    fn bad_checksum_tells_the_line() {
+        let e = parse_lines(&[":02000004803644", ":0400100001020304E3"]).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "line 2: checksum mismatch");
+    }
+
+    #[test]
+    fn missing_eof_record() {
+        let e = parse_lines(&[":02000004803644", ":0400100001020304E2"]).unwrap_err();
+        assert!(e.to_string().contains("no end of file record"), "{e}");
+    }
+}
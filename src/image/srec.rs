@@ -0,0 +1,131 @@
+use std::io::Result;
+
+use super::{decode_hex, invalid, push_data, Image};
+
+pub fn is_srec(head: &[u8]) -> bool {
+    matches!(head, [b'S', d, ..] if d.is_ascii_digit())
+}
+
+pub fn parse(data: &[u8]) -> Result<Image> {
+    let text = std::str::from_utf8(data).map_err(|_| invalid("SREC file is not text"))?;
+
+    let mut image = Image::default();
+    let mut records = 0_u32;
+    for (n, line) in text.lines().enumerate() {
+        let n = n + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| invalid(format!("line {n}: {msg}"));
+
+        let rec = line
+            .strip_prefix('S')
+            .ok_or_else(|| err("record does not start with 'S'"))?;
+        let (t, rec) = rec.split_at_checked(1).ok_or_else(|| err("missing type"))?;
+        let bytes = decode_hex(rec).ok_or_else(|| err("invalid hex digits"))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(err("record length mismatch"));
+        }
+        let sum = bytes.iter().fold(0_u8, |s, b| s.wrapping_add(*b));
+        if sum != 0xff {
+            return Err(err("checksum mismatch"));
+        }
+
+        let addr_len = match t {
+            "0" | "1" | "5" | "9" => 2,
+            "2" | "6" | "8" => 3,
+            "3" | "7" => 4,
+            _ => return Err(err(&format!("unknown record type S{t}"))),
+        };
+        let body = &bytes[1..bytes.len() - 1];
+        if body.len() < addr_len {
+            return Err(err("record too short for its address"));
+        }
+        let (addr, payload) = body.split_at(addr_len);
+        let addr = addr.iter().fold(0, |v, b| (v << 8) | *b as u32);
+        match t {
+            "1" | "2" | "3" => {
+                push_data(&mut image.segments, addr, payload);
+                records += 1;
+            }
+            "7" | "8" | "9" => image.entry = Some(addr),
+            // The number of data records so far, to tell if any got lost
+            "5" | "6" if addr != records => {
+                return Err(err(&format!(
+                    "record count {addr} does not match the {records} data records"
+                )))
+            }
+            // The header carries nothing to load.
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Segment;
+
+    fn parse_lines(lines: &[&str]) -> Result<Image> {
+        parse(lines.join("\n").as_bytes())
+    }
+
+    fn segment(addr: u32, data: &[u8]) -> Segment {
+        Segment {
+            addr,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn address_widths() {
+        let image = parse_lines(&[
+            "S0060000686472BB",
+            "S10510000102E7",
+            "S2062010000304C2",
+            "S30780360000050637",
+        ])
+        .unwrap();
+        let segments = [
+            segment(0x1000, &[1, 2]),
+            segment(0x20_1000, &[3, 4]),
+            segment(0x8036_0000, &[5, 6]),
+        ];
+        assert_eq!(image.segments, segments);
+        assert_eq!(image.entry, None);
+    }
+
+    #[test]
+    fn entry_points() {
+        let cases = [
+            ("S7058036000044", 0x8036_0000),
+            ("S804201000CB", 0x20_1000),
+            ("S9031000EC", 0x1000),
+        ];
+        for (line, entry) in cases {
+            let image = parse_lines(&["S10510000102E7", line]).unwrap();
+            assert_eq!(image.entry, Some(entry), "{line}");
+        }
+    }
+
+    #[test]
+    fn bad_checksum_tells_the_line() {
+        let e = parse_lines(&["S0060000686472BB", "S10510000102E8"]).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "line 2: checksum mismatch");
+    }
+
+    #[test]
+    fn count_record_is_checked() {
+        let data = ["S10510000102E7", "S2062010000304C2", "S30780360000050637"];
+        parse_lines(&[&data[..], &["S5030003F9"]].concat()).unwrap();
+
+        let e = parse_lines(&[&data[..], &["S5030002FA"]].concat()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "line 4: record count 2 does not match the 3 data records"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Talk to Canaan Kendryte SoC mask ROMs over USB.
+//!
+//! ```no_run
+//! use kendryte_boot::{Kendryte, KENDRYTE_VID, K230D_PID, SRAM_RUN_BASE};
+//!
+//! let di = nusb::list_devices()?
+//!     .find(|d| d.vendor_id() == KENDRYTE_VID && d.product_id() == K230D_PID)
+//!     .expect("no device");
+//! let k = Kendryte::open(&di)?;
+//! println!("{}", k.cpu_info()?);
+//! k.load(SRAM_RUN_BASE, std::fs::File::open("payload.bin")?)?;
+//! k.run(SRAM_RUN_BASE)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+mod error;
+pub mod image;
+mod protocol;
+mod transport;
+mod usb;
+
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+pub use error::Error;
+use log::warn;
+pub use protocol::*;
+pub use transport::{Call, DryRun, MockTransport, Transport};
+pub use usb::*;
+
+pub const KENDRYTE_VID: u16 = 0x29f1;
+pub const K230D_PID: u16 = 0x0230;
+
+/// Supported SoCs
+///
+/// The K210 is not listed: its mask ROM only does ISP over UART, so there
+/// is no USB device to match. New chips with a USB loader go here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chip {
+    /// K230 and K230D
+    K230,
+}
+
+impl Chip {
+    pub const ALL: &'static [Chip] = &[Chip::K230];
+
+    pub fn vid(self) -> u16 {
+        match self {
+            Chip::K230 => KENDRYTE_VID,
+        }
+    }
+
+    pub fn pid(self) -> u16 {
+        match self {
+            Chip::K230 => K230D_PID,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Chip::K230 => "k230",
+        }
+    }
+
+    /// What the CPU info reply starts with when the mask ROM is answering,
+    /// and not e.g. a loader from flash with the same IDs
+    pub fn rom_signature(self) -> &'static [u8] {
+        match self {
+            Chip::K230 => b"K230",
+        }
+    }
+
+    /// Take a CPU info reply apart, or `None` if its layout is not known,
+    /// e.g. when something other than the mask ROM answers. The K230 mask
+    /// ROM sends its name first; what the bytes after it mean is not
+    /// documented, so they are only split off.
+    pub fn decode_cpu_info(self, raw: &[u8]) -> Option<CpuInfo> {
+        match self {
+            Chip::K230 if raw.starts_with(self.rom_signature()) => Some(CpuInfo::split(raw)),
+            Chip::K230 => None,
+        }
+    }
+
+    /// Address of a named place in memory, see [`Chip::PRESETS`]
+    pub fn preset(self, name: &str) -> Option<u32> {
+        match (self, name) {
+            (Chip::K230, "sram") => Some(SRAM_RUN_BASE),
+            (Chip::K230, "dram") => Some(DRAM_BASE),
+            (Chip::K230, "rom") => Some(MASK_ROM_BASE),
+            _ => None,
+        }
+    }
+
+    /// Names for [`Chip::preset`]: where code usually runs from in SRAM,
+    /// the start of DRAM, and the mask ROM
+    pub const PRESETS: &'static [&'static str] = &["sram", "dram", "rom"];
+
+    /// Where `selftest` writes by default: the SRAM that code is run from,
+    /// which nothing uses while the mask ROM waits for code
+    pub fn scratch(self) -> u32 {
+        match self {
+            Chip::K230 => SRAM_RUN_BASE,
+        }
+    }
+
+    /// On-chip SRAM that the mask ROM can load code to
+    pub fn sram(self) -> Range<u64> {
+        match self {
+            Chip::K230 => 0x8020_0000..0x8040_0000,
+        }
+    }
+
+    /// The mask ROM itself, which cannot be written
+    pub fn rom(self) -> Range<u64> {
+        match self {
+            Chip::K230 => MASK_ROM_BASE as u64..MASK_ROM_BASE as u64 + 0x1_0000,
+        }
+    }
+
+    /// Find the chip matching a USB vendor and product ID.
+    pub fn from_ids(vid: u16, pid: u16) -> Option<Chip> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|c| c.vid() == vid && c.pid() == pid)
+    }
+}
+
+impl fmt::Display for Chip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Chip {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|c| c.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|c| c.name()).collect();
+                format!("unknown chip '{s}', expected one of: {}", names.join(", "))
+            })
+    }
+}
+
+pub const SRAM_RUN_BASE: u32 = 0x8036_0000;
+pub const DRAM_BASE: u32 = 0x0;
+pub const MASK_ROM_BASE: u32 = 0x9120_0000;
+
+/// Check that `len` bytes at `addr` can be loaded on `chip`, warning when
+/// they are outside of the SRAM, which is all that works before DRAM init.
+pub fn validate_load_region(chip: Chip, addr: u32, len: u64) -> std::result::Result<(), Error> {
+    let region = addr as u64..addr as u64 + len;
+    let invalid = |reason| Error::InvalidRegion {
+        op: "load",
+        addr,
+        len,
+        reason,
+    };
+    if region.end > 1 << 32 {
+        return Err(invalid("it exceeds the 32-bit address space"));
+    }
+    let rom = chip.rom();
+    if region.start < rom.end && rom.start < region.end {
+        return Err(invalid("it overlaps the mask ROM"));
+    }
+    let sram = chip.sram();
+    if region.start < sram.start || region.end > sram.end {
+        warn!(
+            "{len:#x} bytes at {addr:#010x} are outside of SRAM ({:#010x}..{:#010x})",
+            sram.start, sram.end
+        );
+    }
+    Ok(())
+}
@@ -1,231 +1,2245 @@
-use std::io::{self, ErrorKind::TimedOut, Read, Result};
-use std::str::from_utf8;
+mod checksum;
+mod cli;
+mod compress;
+mod config;
+mod monitor;
+
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufRead, IsTerminal, Read, Result, Seek, SeekFrom, Write};
+use std::process::{self, ExitCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
 use std::thread;
-use std::time::{Duration, Instant};
-use std::{fs::File, io::BufReader};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use async_io::{block_on, Timer};
-use clap::{Parser, Subcommand};
-use futures_lite::FutureExt;
-use nusb::{
-    transfer::{ControlIn, ControlOut, ControlType, Direction, Recipient, RequestBuffer},
-    Device, Interface, Speed,
+use checksum::{Digesting, InputDigest};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, FromArgMatches};
+use cli::{Cli, Command, DumpFormat, Endian, Entry, OutputFormat, Width};
+use compress::Compression;
+use config::Config;
+use futures_lite::{FutureExt, StreamExt};
+use kendryte_boot::image::{find_symbol, Format, Image};
+use kendryte_boot::{
+    chunk_size_for, cpu_info_text, port_path, timeout_for, validate_load_region,
+    wait_for_device_gone_with, wait_for_device_with, Backoff, Chip, DryRun, Error, InputError,
+    Kendryte, LinkInfo, Mismatch, OpenOptions, TransferCounts, Transport, CHUNK_SIZE,
+    MASK_ROM_BASE,
 };
+use log::{debug, error, info, log_enabled, warn, Level, LevelFilter};
+use nusb::hotplug::HotplugEvent;
+use nusb::{DeviceInfo, Speed};
+use serde::Serialize;
 
-const KENDRYTE_VID: u16 = 0x29f1;
-const K230D_PID: u16 = 0x0230;
-
-const CLAIM_INTERFACE_TIMEOUT: Duration = Duration::from_secs(1);
-const CLAIM_INTERFACE_PERIOD: Duration = Duration::from_micros(200);
-
-fn claim_interface(d: &Device, ii: u8) -> std::result::Result<Interface, String> {
-    let now = Instant::now();
-    while Instant::now() <= now + CLAIM_INTERFACE_TIMEOUT {
-        match d.claim_interface(ii) {
-            Ok(i) => {
-                return Ok(i);
-            }
-            Err(_) => {
-                thread::sleep(CLAIM_INTERFACE_PERIOD);
-            }
-        }
-    }
-    Err("failure claiming USB interface".into())
-}
-
-const EP0_GET_CPU_INFO: u8 = 0x0;
-const EP0_SET_DATA_ADDRESS: u8 = 0x1;
-const EP0_SET_DATA_LENGTH: u8 = 0x2;
-const EP0_FLUSH_CACHES: u8 = 0x3;
-const EP0_PROG_START: u8 = 0x4;
-
-const SRAM_RUN_BASE: &str = "0x80360000";
-const MASK_ROM_BASE: usize = 0x9120_0000;
-
-const CHUNK_SIZE: usize = 512;
-
-#[derive(Debug, Subcommand)]
-enum Command {
-    /// Print CPU info
-    #[clap(verbatim_doc_comment)]
-    CpuInfo,
-    /// Jump back to mask ROM
-    #[clap(verbatim_doc_comment)]
-    Rom,
-    /// Load binary from file to memory
-    #[clap(verbatim_doc_comment)]
-    Load {
-        #[clap(long, short, value_parser=clap_num::maybe_hex::<u32>, default_value = SRAM_RUN_BASE)]
-        address: u32,
-        file_name: String,
-    },
-    /// Run binary code from file
-    #[clap(verbatim_doc_comment)]
-    Run {
-        #[clap(long, short, value_parser=clap_num::maybe_hex::<u32>, default_value = SRAM_RUN_BASE)]
-        address: u32,
-        file_name: String,
-    },
-}
-
-/// Kendryte mask ROM loader tool
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    /// Command to run
-    #[command(subcommand)]
-    cmd: Command,
+const PROGRESS_PERIOD: Duration = Duration::from_millis(100);
+
+/// Exit code after Ctrl-C, like shells report for SIGINT
+const EXIT_INTERRUPTED: u8 = 130;
+
+/// Exit code for scripts to tell apart what went wrong, see the README
+fn exit_code(e: &Error) -> u8 {
+    match e {
+        Error::Enumerate(_)
+        | Error::DeviceNotFound
+        | Error::NotInRom { .. }
+        | Error::MultipleDevices => 2,
+        Error::Open(_)
+        | Error::Descriptor(_)
+        | Error::NoInterface { .. }
+        | Error::Claim { .. }
+        | Error::Busy { .. }
+        | Error::WinUsb { .. }
+        | Error::AltSetting { .. }
+        | Error::NoEndpoint { .. }
+        | Error::MissingEndpoint { .. }
+        | Error::EndpointDirection { .. } => 3,
+        Error::Transfer { .. }
+        | Error::ChunksFailed { .. }
+        | Error::Repeat { .. }
+        | Error::Deadline { .. } => 4,
+        Error::File { .. }
+        | Error::Image { .. }
+        | Error::TooLarge { .. }
+        | Error::Symbol { .. }
+        | Error::Config { .. } => 5,
+        Error::Verify(_) | Error::InfoMismatch { .. } => 6,
+        Error::InvalidRegion { .. } | Error::Serial { .. } | Error::Hook { .. } => 1,
+    }
 }
 
-fn cmd_in(i: &Interface, buf: &mut [u8], request: u8, val: u32) {
-    let timeout = Duration::from_secs(5);
-    let value = (val >> 16) as u16;
-    let index = val as u16;
-    let length = buf.len() as u16;
-
-    let _res: Result<usize> = {
-        let fut = async {
-            let ci = ControlIn {
-                control_type: ControlType::Vendor,
-                recipient: Recipient::Device,
-                request,
-                value,
-                index,
-                length,
-            };
-            let comp = i.control_in(ci).await;
-            comp.status.map_err(std::io::Error::other)?;
+/// Set by Ctrl-C during a transfer, which then stops between chunks
+static STOP: LazyLock<Arc<AtomicBool>> = LazyLock::new(Default::default);
+/// Whether a transfer is going on that Ctrl-C should stop cleanly
+static TRANSFERRING: AtomicBool = AtomicBool::new(false);
+/// Whether `watch` is waiting for boards, which Ctrl-C should stop cleanly
+static WATCHING: AtomicBool = AtomicBool::new(false);
+/// Bytes transferred so far, over all the times the device is opened
+static COUNTS: LazyLock<Arc<TransferCounts>> = LazyLock::new(Default::default);
+/// Whether stdout is for the JSON result only
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
 
-            let n = comp.data.len();
-            buf[..n].copy_from_slice(&comp.data);
-            Ok(n)
-        };
+/// Where what commands print goes: stdout, unless that is for the JSON
+/// result, then stderr
+fn data_out() -> Box<dyn Write> {
+    match JSON_OUTPUT.load(Ordering::Relaxed) {
+        true => Box::new(io::stderr()),
+        false => Box::new(io::stdout()),
+    }
+}
 
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(TimedOut.into())
-        }))
+/// `println!` to [`data_out`]
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        match JSON_OUTPUT.load(Ordering::Relaxed) {
+            true => eprintln!($($arg)*),
+            false => println!($($arg)*),
+        }
     };
 }
+/// With --quiet, nothing but what was asked for and errors is printed
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Stop a transfer at the next chunk, or quit right away if there is none
+/// or this is the second Ctrl-C.
+fn on_ctrl_c() {
+    if TRANSFERRING.load(Ordering::SeqCst) && !STOP.swap(true, Ordering::SeqCst) {
+        warn!("Stopping after the current transfer, press Ctrl-C again to quit now");
+        return;
+    }
+    if WATCHING.load(Ordering::SeqCst) && !STOP.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    process::exit(EXIT_INTERRUPTED.into());
+}
+
+/// Run `transfer` so that Ctrl-C stops it between chunks, and clear the OUT
+/// endpoint if it did, so that the device takes the next transfer.
+fn stoppable<R>(k: &Kendryte<impl Transport>, transfer: impl FnOnce() -> Result<R>) -> Result<R> {
+    TRANSFERRING.store(true, Ordering::SeqCst);
+    let r = transfer();
+    TRANSFERRING.store(false, Ordering::SeqCst);
+    if STOP.load(Ordering::SeqCst) {
+        let (out_ep, _) = k.endpoints();
+        if let Err(e) = k.transport().clear_halt(out_ep) {
+            debug!("clearing halt on endpoint {out_ep:#04x} failed: {e}");
+        }
+    }
+    r
+}
 
-fn dev_info(i: &Interface) {
-    let mut buf = [0; 0x20];
-    cmd_in(i, &mut buf, EP0_GET_CPU_INFO, 0);
-    let reply = from_utf8(&buf).unwrap();
-    println!("Device says: {reply}");
+/// Single-line transfer progress; only a final summary when not on a TTY.
+struct Progress {
+    total: Option<u64>,
+    done: u64,
+    start: Instant,
+    drawn: Instant,
+    tty: bool,
 }
 
-fn cmd_out(i: &Interface, request: u8, val: u32) {
-    let timeout = Duration::from_secs(5);
-    let value = (val >> 16) as u16;
-    let index = val as u16;
+impl Progress {
+    fn new(total: Option<u64>) -> Self {
+        let now = Instant::now();
+        Self {
+            total,
+            done: 0,
+            start: now,
+            drawn: now,
+            tty: io::stderr().is_terminal() && !QUIET.load(Ordering::Relaxed),
+        }
+    }
 
-    let _res: Result<()> = {
-        let fut = async {
-            let co = ControlOut {
-                control_type: ControlType::Vendor,
-                recipient: Recipient::Device,
-                request,
-                value,
-                index,
-                data: &[],
-            };
-            let comp = i.control_out(co).await;
-            comp.status.map_err(std::io::Error::other)?;
-            Ok(())
+    fn rate(&self) -> f64 {
+        let secs = self.start.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            self.done as f64 / 1024.0 / secs
+        } else {
+            0.0
+        }
+    }
+
+    fn draw(&self) {
+        let done = self.done;
+        let rate = self.rate();
+        match self.total {
+            Some(total) => {
+                let pct = (done * 100).checked_div(total).unwrap_or(100);
+                eprint!("\r{done}/{total} bytes ({pct:3}%) {rate:.1} KiB/s");
+            }
+            // Streaming from a pipe, there is nothing to relate to.
+            None => eprint!("\r{done} bytes {rate:.1} KiB/s"),
+        }
+    }
+
+    fn update(&mut self, n: usize) {
+        self.done += n as u64;
+        if self.tty && self.drawn.elapsed() >= PROGRESS_PERIOD {
+            self.drawn = Instant::now();
+            self.draw();
+        }
+    }
+
+    /// Print the summary, telling apart the time it took to get the data.
+    fn finish(&self, reading: Duration) {
+        if self.tty {
+            self.draw();
+            eprintln!();
+        }
+        let elapsed = self.start.elapsed();
+        let usb = elapsed.saturating_sub(reading).as_secs_f64();
+        let secs = elapsed.as_secs_f64();
+        let rate = self.rate() / 1024.0;
+        info!(
+            "Sent {} bytes in {secs:.2}s ({rate:.2} MiB/s): {usb:.2}s on USB, {:.2}s reading input",
+            self.done,
+            reading.as_secs_f64()
+        );
+    }
+}
+
+/// Adds up the time spent reading from `inner`
+struct TimedReader<'a, R> {
+    inner: R,
+    spent: &'a Cell<Duration>,
+}
+
+impl<R: Read> Read for TimedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let start = Instant::now();
+        let n = self.inner.read(buf);
+        self.spent.set(self.spent.get() + start.elapsed());
+        n
+    }
+}
+
+/// Print `data` like xxd does, with addresses starting at `addr`, in pairs
+/// of bytes or in `words` of the given width and byte order.
+fn print_hex(
+    out: &mut impl Write,
+    addr: u32,
+    data: &[u8],
+    words: Option<(Width, Endian)>,
+) -> Result<()> {
+    let group = words.map_or(2, |(w, _)| w.bytes());
+    let width = 32 + 16 / group - 1;
+    for (n, line) in data.chunks(16).enumerate() {
+        let hex: Vec<_> = line
+            .chunks(group)
+            .map(|g| match words {
+                Some((_, e)) => format!("{:0w$x}", e.decode(g), w = g.len() * 2),
+                None => hex_string(g),
+            })
+            .collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                true => b as char,
+                false => '.',
+            })
+            .collect();
+        let addr = addr as usize + n * 16;
+        writeln!(out, "{addr:08x}: {:width$}  {ascii}", hex.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Print `data` as lines of a C array initializer.
+fn print_c_array(out: &mut impl Write, data: &[u8]) -> Result<()> {
+    for line in data.chunks(16) {
+        let bytes: Vec<_> = line.iter().map(|b| format!("0x{b:02x},")).collect();
+        writeln!(out, "    {}", bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Read `length` bytes from `addr` and write them to `out`, called `name`
+/// in errors. Failing to write is a file error, not a transfer one.
+fn dump(
+    k: &Kendryte<impl Transport>,
+    addr: u32,
+    length: u32,
+    out: &mut impl Write,
+    name: &str,
+    format: DumpFormat,
+    words: Option<(Width, Endian)>,
+) -> std::result::Result<(), Error> {
+    let context = format!("Failed to dump from {addr:#010x}");
+    if format == DumpFormat::CArray {
+        writeln!(out, "const uint8_t mem_{addr:08x}[{length:#x}] = {{")
+            .map_err(Error::file(name))?;
+    }
+    let mut buf = [0_u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < length {
+        // The last chunk may be shorter than the others.
+        let len = (length - offset).min(CHUNK_SIZE as u32) as usize;
+        let chunk_addr = addr + offset;
+        k.read(chunk_addr, &mut buf[..len])
+            .map_err(Error::transfer(context.clone()))?;
+        match format {
+            DumpFormat::Hex => print_hex(out, chunk_addr, &buf[..len], words),
+            DumpFormat::CArray => print_c_array(out, &buf[..len]),
+            DumpFormat::Binary => out.write_all(&buf[..len]),
+        }
+        .map_err(Error::file(name))?;
+        offset += len as u32;
+    }
+    if format == DumpFormat::CArray {
+        writeln!(out, "}};").map_err(Error::file(name))?;
+    }
+    out.flush().map_err(Error::file(name))
+}
+
+/// Load `size` bytes, or until EOF if unknown, from `reader` to `addr`
+fn load(
+    k: &Kendryte<impl Transport>,
+    addr: u32,
+    reader: impl Read,
+    size: Option<u64>,
+    set_len: bool,
+) -> Result<()> {
+    let len = match (set_len, size) {
+        (true, Some(size)) => Some(
+            u32::try_from(size)
+                .map_err(|_| io::Error::other("file does not fit in 32-bit address space"))?,
+        ),
+        _ => None,
+    };
+    let mut progress = Progress::new(size);
+    let reading = Cell::new(Duration::ZERO);
+    let reader = TimedReader {
+        inner: io::BufReader::new(reader),
+        spent: &reading,
+    };
+    let mut crc = crc32fast::Hasher::new();
+    stoppable(k, || {
+        k.load_with_progress(addr, reader, len, |b| {
+            progress.update(b.len());
+            crc.update(b);
+        })
+    })?;
+    progress.finish(reading.get());
+    info!("CRC32: {:08x}", crc.finalize());
+    Ok(())
+}
+
+fn load_image(k: &Kendryte<impl Transport>, image: &Image, set_len: bool) -> Result<()> {
+    for s in &image.segments {
+        info!("Segment at {:#010x}: {} bytes", s.addr, s.data.len());
+    }
+    let mut progress = Progress::new(Some(image.len()));
+    let mut crc = crc32fast::Hasher::new();
+    stoppable(k, || {
+        k.load_image(image, set_len, |b| {
+            progress.update(b.len());
+            crc.update(b);
+        })
+    })?;
+    // The image is in memory already.
+    progress.finish(Duration::ZERO);
+    info!("CRC32: {:08x}", crc.finalize());
+    Ok(())
+}
+
+/// The first bytes of `file`, which is rewound after
+fn read_head(file: &mut File) -> Result<Vec<u8>> {
+    let mut head = [0_u8; 4];
+    let n = file.read(&mut head)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(head[..n].to_vec())
+}
+
+/// `len` rounded up to a multiple of `align`
+fn align_up(len: u64, align: u64) -> u64 {
+    len.div_ceil(align) * align
+}
+
+/// `len` bytes padded to at least `pad_to`, then to a multiple of `align`
+fn padded_len(len: u64, align: u64, pad_to: Option<u64>) -> u64 {
+    align_up(pad_to.map_or(len, |p| p.max(len)), align)
+}
+
+/// Appends `fill` bytes to `inner` up to `pad_to` bytes, and then to a
+/// multiple of `align` bytes
+struct Padded<R> {
+    inner: R,
+    align: u64,
+    pad_to: Option<u64>,
+    fill: u8,
+    len: u64,
+    /// Set at the end of `inner`, to how long it all has to be
+    end: Option<u64>,
+}
+
+impl<R: Read> Read for Padded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let end = match self.end {
+            Some(end) => end,
+            None => {
+                let n = self.inner.read(buf)?;
+                self.len += n as u64;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                if let Some(max) = self.pad_to.filter(|&p| p < self.len) {
+                    let msg = format!("{:#x} bytes are more than --pad-to {max:#x}", self.len);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                }
+                *self
+                    .end
+                    .insert(padded_len(self.len, self.align, self.pad_to))
+            }
         };
+        let n = (end - self.len).min(buf.len() as u64) as usize;
+        buf[..n].fill(self.fill);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Words to send after the data
+#[derive(Clone, Copy, Debug, Default)]
+struct Trailer {
+    length: bool,
+    crc32: bool,
+}
+
+impl Trailer {
+    fn len(&self) -> u64 {
+        4 * (self.length as u64 + self.crc32 as u64)
+    }
+
+    /// The trailer for `len` bytes of data with CRC32 `crc`
+    fn bytes(&self, len: u64, crc: u32) -> Vec<u8> {
+        let mut b = Vec::new();
+        if self.length {
+            b.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        if self.crc32 {
+            b.extend_from_slice(&crc.to_le_bytes());
+        }
+        b
+    }
+}
+
+/// Appends `trailer` to `inner` once it ends
+struct WithTrailer<R> {
+    inner: R,
+    trailer: Trailer,
+    crc: crc32fast::Hasher,
+    len: u64,
+    tail: Option<io::Cursor<Vec<u8>>>,
+}
+
+impl<R: Read> Read for WithTrailer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.tail.is_none() {
+            let n = self.inner.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.crc.update(&buf[..n]);
+                self.len += n as u64;
+                return Ok(n);
+            }
+            let crc = self.crc.clone().finalize();
+            let tail = self.trailer.bytes(self.len, crc);
+            if !tail.is_empty() {
+                info!("Trailer after {} bytes: {}", self.len, hex_string(&tail));
+            }
+            self.tail = Some(io::Cursor::new(tail));
+        }
+        self.tail.as_mut().unwrap().read(buf)
+    }
+}
+
+/// Check that `file_name` can be read, unless it is stdin.
+fn check_input(file_name: &str) -> std::result::Result<(), Error> {
+    if file_name == "-" {
+        return Ok(());
+    }
+    let file = File::open(file_name).map_err(Error::file(file_name))?;
+    let meta = file.metadata().map_err(Error::file(file_name))?;
+    if meta.is_dir() {
+        let e = io::Error::new(io::ErrorKind::IsADirectory, "is a directory");
+        return Err(Error::file(file_name)(e));
+    }
+    Ok(())
+}
+
+/// How to load files, from the command line
+struct LoadOptions {
+    chip: Chip,
+    set_len: bool,
+    verify: bool,
+    force: bool,
+    /// Bytes to skip at the start of the file, which makes it raw data
+    skip: u64,
+    /// Bytes to send from the file after the skipped ones, also making it
+    /// raw data, instead of all up to the end
+    length: Option<u64>,
+    /// Pad the data to a multiple of this
+    align: u64,
+    /// Pad raw data to this many bytes, failing if there are more
+    pad_to: Option<u64>,
+    /// What to pad with
+    pad_byte: u8,
+    /// What to send after the data, and after padding
+    trailer: Trailer,
+    /// Symbol to return the address of instead of the entry point
+    symbol: Option<String>,
+    /// Hashes the data read from the file, for --manifest
+    digest: Option<InputDigest>,
+}
+
+impl LoadOptions {
+    /// Whether to load the file as is, without looking for an image format
+    fn raw(&self) -> bool {
+        self.skip != 0 || self.length.is_some()
+    }
+
+    fn validate(&self, addr: u32, len: u64) -> std::result::Result<(), Error> {
+        match self.force {
+            true => Ok(()),
+            false => validate_load_region(self.chip, addr, len),
+        }
+    }
+}
+
+/// Load a raw binary to `address`, or a structured image to the addresses
+/// it contains. Returns the image's entry point, if any.
+fn load_file(
+    k: &Kendryte<impl Transport>,
+    address: u32,
+    file_name: &str,
+    opts: &LoadOptions,
+) -> std::result::Result<Option<u32>, Error> {
+    if let Some(d) = &opts.digest {
+        d.reset();
+    }
+    if file_name == "-" {
+        return load_stream(k, address, "stdin", io::stdin().lock(), opts);
+    }
+    let mut data = File::open(file_name).map_err(Error::file(file_name))?;
+    let head = read_head(&mut data).map_err(Error::file(file_name))?;
+    if let Some(c) = Compression::detect(file_name, &head) {
+        debug!("Decompressing {file_name} as {c:?}");
+        let reader = c.decoder(data).map_err(Error::file(file_name))?;
+        return load_stream(k, address, file_name, io::BufReader::new(reader), opts);
+    }
+    let format = match opts.raw() {
+        false => Format::detect(&head),
+        true => None,
+    };
+
+    let Some(format) = format else {
+        let size = data.metadata().map_err(Error::file(file_name))?.len();
+        let size = size.saturating_sub(opts.skip);
+        let size = opts.length.map_or(size, |l| size.min(l));
+        data.seek(SeekFrom::Start(opts.skip))
+            .map_err(Error::file(file_name))?;
+        load_raw(k, address, file_name, (&data).take(size), Some(size), opts)?;
+        if opts.verify {
+            data.seek(SeekFrom::Start(opts.skip))
+                .map_err(Error::file(file_name))?;
+            k.verify(address, io::BufReader::new(&data).take(size))
+                .map_err(verify_error(file_name))?;
+            info!("Verified {file_name} at {address:#010x}");
+        }
+        return Ok(None);
+    };
+
+    let mut bytes = Vec::new();
+    data.read_to_end(&mut bytes)
+        .map_err(Error::file(file_name))?;
+    load_image_bytes(k, file_name, format, &bytes, opts)
+}
+
+/// Load from stdin or a decompressing reader, streaming raw data until EOF.
+/// Images are read in full to parse them, as is raw data to be verified,
+/// since the stream cannot rewind.
+fn load_stream(
+    k: &Kendryte<impl Transport>,
+    address: u32,
+    name: &str,
+    mut stream: impl BufRead,
+    opts: &LoadOptions,
+) -> std::result::Result<Option<u32>, Error> {
+    let format = match opts.raw() {
+        false => {
+            let head = stream.fill_buf().map_err(Error::file(name))?;
+            Format::detect(&head[..head.len().min(4)])
+        }
+        true => {
+            io::copy(&mut (&mut stream).take(opts.skip), &mut io::sink())
+                .map_err(Error::file(name))?;
+            None
+        }
+    };
+    let mut stream = stream.take(opts.length.unwrap_or(u64::MAX));
+    if format.is_none() && !opts.verify {
+        load_raw(k, address, name, stream, None, opts)?;
+        return Ok(None);
+    }
+
+    let mut bytes = Vec::new();
+    stream.read_to_end(&mut bytes).map_err(Error::file(name))?;
+    if let Some(format) = format {
+        return load_image_bytes(k, name, format, &bytes, opts);
+    }
+    load_raw(k, address, name, &bytes[..], Some(bytes.len() as u64), opts)?;
+    k.verify(address, &bytes[..]).map_err(verify_error(name))?;
+    info!("Verified {name} at {address:#010x}");
+    Ok(None)
+}
 
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(TimedOut.into())
-        }))
+/// Load raw data to `address`, of unknown size when streaming
+fn load_raw(
+    k: &Kendryte<impl Transport>,
+    address: u32,
+    name: &str,
+    reader: impl Read,
+    size: Option<u64>,
+    opts: &LoadOptions,
+) -> std::result::Result<(), Error> {
+    if let Some(name) = &opts.symbol {
+        return Err(Error::Symbol {
+            name: name.clone(),
+            reason: "raw data has no symbols".into(),
+        });
+    }
+    let reader = Digesting::new(reader, opts.digest.as_ref());
+    if let (Some(len), Some(max)) = (size, opts.pad_to) {
+        if len > max {
+            return Err(Error::TooLarge { len, max });
+        }
+    }
+    let padded = size.map(|s| padded_len(s, opts.align, opts.pad_to));
+    if padded != size {
+        info!(
+            "Padding with {} bytes of {:#04x}",
+            padded.unwrap_or(0) - size.unwrap_or(0),
+            opts.pad_byte
+        );
+    }
+    let size = padded.map(|s| s + opts.trailer.len());
+    opts.validate(address, size.unwrap_or(0))?;
+    if size.is_none() && opts.set_len {
+        debug!("Size unknown, not setting data length");
+    }
+    let reader = WithTrailer {
+        inner: Padded {
+            inner: reader,
+            align: opts.align,
+            pad_to: opts.pad_to,
+            fill: opts.pad_byte,
+            len: 0,
+            end: None,
+        },
+        trailer: opts.trailer,
+        crc: crc32fast::Hasher::new(),
+        len: 0,
+        tail: None,
     };
+    load(k, address, reader, size, opts.set_len).map_err(load_error(
+        name,
+        format!("Failed to load to {address:#010x}"),
+    ))
 }
 
-fn set_code_addr(i: &Interface, addr: u32) {
-    cmd_out(i, EP0_SET_DATA_ADDRESS, addr);
+/// Wrap a load or verify error like [`Error::transfer`], unless it is data
+/// that read back wrong, or reading `name` that failed.
+fn load_error(name: &str, context: String) -> impl FnOnce(io::Error) -> Error {
+    let name = name.to_string();
+    move |e| match e.get_ref() {
+        Some(inner) if inner.is::<Mismatch>() => Error::Verify(e),
+        Some(inner) if inner.is::<InputError>() => Error::file(name)(e),
+        _ => Error::transfer(context)(e),
+    }
 }
 
-fn run_code(i: &Interface, addr: u32) {
-    cmd_out(i, EP0_PROG_START, addr);
+fn verify_error(name: &str) -> impl FnOnce(io::Error) -> Error {
+    load_error(name, format!("Failed to verify {name}"))
 }
 
-fn load(i: &Interface, usb_out_addr: u8, addr: u32, file: &File) {
-    set_code_addr(&i, addr);
-    let mut reader = BufReader::new(file);
-    let mut buf = [0_u8; CHUNK_SIZE];
+/// What --manifest records about a load
+#[derive(Serialize)]
+struct Manifest<'a> {
+    file: &'a str,
+    /// Of the data read from the file, after decompressing it and within
+    /// --skip and --length; null when built without SHA-256
+    sha256: Option<String>,
+    /// Sent to the device, with padding and trailers
+    bytes: u64,
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+    device_serial: Option<&'a str>,
+    success: bool,
+    error: Option<String>,
+}
+
+/// Write the --manifest for loading `file_name` to `path`.
+fn write_manifest<T>(
+    path: &str,
+    file_name: &str,
+    opts: &LoadOptions,
+    bytes: u64,
+    target: &Target,
+    result: &std::result::Result<T, Error>,
+) -> std::result::Result<(), Error> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let manifest = Manifest {
+        file: file_name,
+        sha256: opts.digest.as_ref().and_then(InputDigest::finish),
+        bytes,
+        timestamp,
+        device_serial: target.info.as_ref().and_then(|d| d.serial_number()),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    let json = serde_json::to_string_pretty(&manifest).expect("manifest serializes");
+    fs::write(path, json + "\n").map_err(Error::file(path))?;
+    debug!("Wrote manifest to {path}");
+    Ok(())
+}
+
+/// Parse an image from `bytes` and load it, see [`load_file`]
+fn load_image_bytes(
+    k: &Kendryte<impl Transport>,
+    file_name: &str,
+    format: Format,
+    bytes: &[u8],
+    opts: &LoadOptions,
+) -> std::result::Result<Option<u32>, Error> {
+    if let Some(d) = &opts.digest {
+        d.update(bytes);
+    }
+    let image_error = |source| Error::Image {
+        path: file_name.into(),
+        source,
+    };
+    let mut image = Image::parse(format, bytes).map_err(image_error)?;
+    if let Some(name) = &opts.symbol {
+        let addr = find_symbol(format, bytes, name).map_err(image_error)?;
+        let addr = addr.ok_or_else(|| Error::Symbol {
+            name: name.clone(),
+            reason: format!("not found in {file_name}"),
+        })?;
+        debug!("Symbol {name} is at {addr:#010x}");
+        image.entry = Some(addr);
+    }
+    if opts.pad_to.is_some() {
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "--pad-to is for raw data only");
+        return Err(image_error(e));
+    }
+    for s in &mut image.segments {
+        let len = align_up(s.data.len() as u64, opts.align);
+        s.data.resize(len as usize, opts.pad_byte);
+        let crc = crc32fast::hash(&s.data);
+        let trailer = opts.trailer.bytes(len, crc);
+        s.data.extend_from_slice(&trailer);
+    }
+    for s in &image.segments {
+        opts.validate(s.addr, s.data.len() as u64)?;
+    }
+    load_image(k, &image, opts.set_len)
+        .map_err(load_error(file_name, format!("Failed to load {file_name}")))?;
+    if opts.verify {
+        for s in &image.segments {
+            k.verify(s.addr, &s.data[..])
+                .map_err(verify_error(file_name))?;
+        }
+        info!("Verified {file_name}");
+    }
+    Ok(image.entry)
+}
+
+const REPL_HELP: &str = "commands: load <file> [address], peek <address>, \
+    poke <address> <value>, run [address], info, quit";
+
+/// Run one line of interactive input. Returns false when done.
+fn repl_command(
+    k: &Kendryte<impl Transport>,
+    opts: &LoadOptions,
+    line: &str,
+) -> std::result::Result<bool, String> {
+    let addr = |a: Option<&str>| match a {
+        Some(a) => cli::parse_address(a).map_err(|e| format!("bad address '{a}': {e}")),
+        None => Ok(kendryte_boot::SRAM_RUN_BASE),
+    };
+    let mut words = line.split_whitespace();
+    let Some(cmd) = words.next() else {
+        return Ok(true);
+    };
+    match cmd.to_lowercase().as_str() {
+        "load" | "l" => {
+            let file_name = words.next().ok_or("load needs a file name")?;
+            let address = addr(words.next())?;
+            load_file(k, address, file_name, opts).map_err(|e| e.to_string())?;
+        }
+        "peek" | "r" => {
+            let address = addr(Some(words.next().ok_or("peek needs an address")?))?;
+            let mut buf = [0_u8; 4];
+            k.read(address, &mut buf).map_err(|e| e.to_string())?;
+            outln!("{address:#010x}: {:#010x}", u32::from_le_bytes(buf));
+        }
+        "poke" | "w" => {
+            let address = addr(Some(words.next().ok_or("poke needs an address")?))?;
+            let v = words.next().ok_or("poke needs a value")?;
+            let value =
+                clap_num::maybe_hex::<u32>(v).map_err(|e| format!("bad value '{v}': {e}"))?;
+            k.load(address, &value.to_le_bytes()[..])
+                .map_err(|e| e.to_string())?;
+        }
+        "run" | "g" => {
+            let address = addr(words.next())?;
+            k.flush_caches().map_err(|e| e.to_string())?;
+            k.run(address).map_err(|e| e.to_string())?;
+        }
+        "info" | "i" => outln!("{}", k.cpu_info().map_err(|e| e.to_string())?),
+        "quit" | "exit" | "q" => return Ok(false),
+        "help" | "?" => outln!("{REPL_HELP}"),
+        _ => return Err(format!("unknown command '{cmd}', {REPL_HELP}")),
+    }
+    Ok(true)
+}
+
+/// Read commands from stdin until it ends or says quit
+fn interactive(k: &Kendryte<impl Transport>, opts: &LoadOptions) -> Result<()> {
+    let tty = io::stdin().is_terminal();
+    let mut line = String::new();
     loop {
-        let len = reader.read(&mut buf[..]).unwrap();
-        if len == 0 {
-            break;
-        }
-        let _: Result<()> = {
-            let timeout = Duration::from_secs(5);
-            let fut = async {
-                let comp = i.bulk_out(usb_out_addr, buf[..len].to_vec()).await;
-                comp.status.map_err(io::Error::other)?;
-                Ok(())
+        if tty {
+            let mut out = data_out();
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        // Allow for comments in scripts piped in.
+        let cmd = line.split('#').next().unwrap_or_default();
+        match repl_command(k, opts, cmd) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => error!("{e}"),
+        }
+    }
+}
+
+fn device_line(di: &DeviceInfo) -> String {
+    let serial = di.serial_number().unwrap_or("-");
+    let ms = di.manufacturer_string().unwrap_or("-");
+    let ps = di.product_string().unwrap_or("-");
+    let bus = di.bus_number();
+    let addr = di.device_address();
+    let port = port_path(di).unwrap_or("-".into());
+    let chip = Chip::from_ids(di.vendor_id(), di.product_id())
+        .map(|c| c.name())
+        .unwrap_or("-");
+    format!("{chip:6} {serial:16} {ms} {ps} (bus {bus:03} address {addr:03} port {port})")
+}
+
+fn print_devices(devices: &[DeviceInfo]) {
+    for di in devices {
+        outln!("{}", device_line(di));
+    }
+}
+
+/// Print the descriptors of `di` down to every endpoint.
+fn describe(di: &DeviceInfo) -> std::result::Result<(), Error> {
+    outln!("{}", device_line(di));
+    outln!(
+        "  ID {:04x}:{:04x} version {:04x} class {:#04x} subclass {:#04x} protocol {:#04x}",
+        di.vendor_id(),
+        di.product_id(),
+        di.device_version(),
+        di.class(),
+        di.subclass(),
+        di.protocol()
+    );
+    let d = di.open().map_err(Error::Open)?;
+    for c in d.configurations() {
+        outln!(
+            "  configuration {}: {} interface(s), max power {} mA",
+            c.configuration_value(),
+            c.num_interfaces(),
+            c.max_power() as u32 * 2
+        );
+        for s in c.interface_alt_settings() {
+            outln!(
+                "    interface {} alt setting {}: class {:#04x} subclass {:#04x} protocol {:#04x}",
+                s.interface_number(),
+                s.alternate_setting(),
+                s.class(),
+                s.subclass(),
+                s.protocol()
+            );
+            for e in s.endpoints() {
+                outln!(
+                    "      endpoint {:#04x} {:?} {:?} max packet size {}",
+                    e.address(),
+                    e.direction(),
+                    e.transfer_type(),
+                    e.max_packet_size()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The device a command ran on, for `--output-format json`
+#[derive(Serialize)]
+struct DeviceReport {
+    chip: String,
+    vid: u16,
+    pid: u16,
+    serial: Option<String>,
+    bus: u8,
+    address: u8,
+    port: Option<String>,
+    speed: String,
+    max_packet_size: usize,
+}
+
+impl DeviceReport {
+    fn new(chip: Chip, di: &DeviceInfo, link: LinkInfo) -> Self {
+        Self {
+            chip: chip.to_string(),
+            vid: di.vendor_id(),
+            pid: di.product_id(),
+            serial: di.serial_number().map(String::from),
+            bus: di.bus_number(),
+            address: di.device_address(),
+            port: port_path(di),
+            speed: format!("{:?}", link.speed),
+            max_packet_size: link.max_packet_size,
+        }
+    }
+}
+
+/// Outcome of a command, printed as JSON with `--output-format json`
+#[derive(Serialize)]
+struct CommandResult {
+    command: String,
+    success: bool,
+    /// The error message, or null
+    error: Option<String>,
+    exit_code: u8,
+    /// Bytes sent to the device
+    bytes_out: u64,
+    /// Bytes received from the device
+    bytes_in: u64,
+    duration_ms: u64,
+    /// Null when no device was needed or none was found
+    device: Option<DeviceReport>,
+}
+
+/// An error as printed to stderr with `--output-format json`
+#[derive(Serialize)]
+struct ErrorReport {
+    error: String,
+    /// See [`Error::kind`]
+    kind: &'static str,
+    /// Where in the data a transfer failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+}
+
+fn print_error_json(e: &Error) {
+    let report = ErrorReport {
+        error: e.to_string(),
+        kind: e.kind(),
+        offset: e.offset(),
+    };
+    let json = serde_json::to_string(&report).expect("report serializes");
+    eprintln!("{json}");
+}
+
+#[derive(Serialize)]
+struct CpuInfoReport {
+    cpu_info: String,
+    cpu_info_hex: String,
+    /// The printable start of the reply, if the chip's layout is known
+    text: Option<String>,
+    /// Hex of the binary fields after `text`
+    data_hex: Option<String>,
+    chip: String,
+    vid: u16,
+    pid: u16,
+    serial: Option<String>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    speed: String,
+    max_packet_size: usize,
+}
+
+fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Print the CPU info fields `chip` knows about, or the reply as it is if
+/// its layout is not known or `raw` is set.
+fn print_cpu_info(reply: &[u8], chip: Chip, raw: bool) {
+    match chip.decode_cpu_info(reply) {
+        Some(info) if !raw => {
+            outln!("Text: {}", info.text);
+            if !info.data.is_empty() {
+                outln!(
+                    "Data: {} ({} bytes)",
+                    hex_string(&info.data),
+                    info.data.len()
+                );
+            }
+        }
+        _ => {
+            outln!("{}", cpu_info_text(reply));
+            outln!("{}", hex_string(reply));
+        }
+    }
+}
+
+fn cpu_info_json(raw: &[u8], target: &Target) {
+    let di = target.info.as_ref();
+    let decoded = target.chip.decode_cpu_info(raw);
+    let report = CpuInfoReport {
+        cpu_info: cpu_info_text(raw),
+        cpu_info_hex: hex_string(raw),
+        text: decoded.as_ref().map(|i| i.text.clone()),
+        data_hex: decoded.map(|i| hex_string(&i.data)),
+        chip: target.chip.to_string(),
+        vid: di.map_or(target.chip.vid(), |d| d.vendor_id()),
+        pid: di.map_or(target.chip.pid(), |d| d.product_id()),
+        serial: di.and_then(|d| d.serial_number()).map(String::from),
+        manufacturer: di.and_then(|d| d.manufacturer_string()).map(String::from),
+        product: di.and_then(|d| d.product_string()).map(String::from),
+        speed: format!("{:?}", target.link.speed),
+        max_packet_size: target.link.max_packet_size,
+    };
+    let json = serde_json::to_string_pretty(&report).expect("report serializes");
+    outln!("{json}");
+}
+
+/// USB IDs to look for, and the chip each of them stands for
+struct ChipIds(Vec<(Chip, u16, u16)>);
+
+impl ChipIds {
+    /// IDs of `chips`, with the vendor and/or product ID replaced if given
+    fn new(chips: &[Chip], vid: Option<u16>, pid: Option<u16>) -> Self {
+        let ids = chips
+            .iter()
+            .map(|&c| (c, vid.unwrap_or(c.vid()), pid.unwrap_or(c.pid())))
+            .collect();
+        Self(ids)
+    }
+
+    fn chip(&self, di: &DeviceInfo) -> Option<Chip> {
+        let ids = (di.vendor_id(), di.product_id());
+        self.0
+            .iter()
+            .find(|&&(_, vid, pid)| (vid, pid) == ids)
+            .map(|&(c, ..)| c)
+    }
+
+    /// Connected devices with these IDs, ordered by bus and port so that
+    /// --index picks the same one each time
+    fn devices(&self) -> std::result::Result<Vec<DeviceInfo>, Error> {
+        let devices = nusb::list_devices().map_err(Error::Enumerate)?;
+        let mut devices: Vec<_> = devices.filter(|d| self.chip(d).is_some()).collect();
+        devices.sort_by_key(|d| (d.bus_number(), port_path(d), d.device_address()));
+        Ok(devices)
+    }
+}
+
+/// Find the one device matching `ids` and `sel`, if connected.
+fn find_device(ids: &ChipIds, sel: &Selection) -> std::result::Result<Option<DeviceInfo>, Error> {
+    let mut devices = ids.devices()?;
+    devices.retain(|d| sel.matches(d));
+    if let Some(i) = sel.index {
+        return Ok(devices.into_iter().nth(i));
+    }
+    match devices.len() {
+        0 => Ok(None),
+        1 => Ok(Some(devices.remove(0))),
+        _ => {
+            print_devices(&devices);
+            Err(Error::MultipleDevices)
+        }
+    }
+}
+
+/// Which of the matching devices to use
+struct Selection<'a> {
+    serial: Option<&'a str>,
+    /// See [`port_path`]
+    port: Option<&'a str>,
+    /// Of the devices that match, see [`ChipIds::devices`]
+    index: Option<usize>,
+}
+
+impl Selection<'_> {
+    fn matches(&self, di: &DeviceInfo) -> bool {
+        self.serial.is_none_or(|s| di.serial_number() == Some(s))
+            && self
+                .port
+                .is_none_or(|p| port_path(di).as_deref() == Some(p))
+    }
+}
+
+/// Like [`find_device`], polling until the device shows up or `timeout`.
+fn wait_device(
+    ids: &ChipIds,
+    sel: &Selection,
+    timeout: Duration,
+    backoff: &Backoff,
+) -> std::result::Result<DeviceInfo, Error> {
+    let start = Instant::now();
+    let mut retry = 0;
+    loop {
+        if let Some(di) = find_device(ids, sel)? {
+            return Ok(di);
+        }
+        let delay = match backoff.delay(retry) {
+            Some(_) if start.elapsed() >= timeout => None,
+            delay => delay,
+        };
+        let Some(delay) = delay else {
+            return Err(Error::DeviceNotFound);
+        };
+        if retry == 0 {
+            info!("Waiting for device...");
+        }
+        retry += 1;
+        debug!("Device not there, retry {retry} in {delay:?}");
+        thread::sleep(delay);
+    }
+}
+
+fn init_logger(verbose: u8, quiet: u8) {
+    let levels = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    let level = match quiet {
+        0 => 3 + verbose as usize,
+        q => 2_usize.saturating_sub(q as usize),
+    };
+    env_logger::Builder::new()
+        .filter_level(levels[level.min(levels.len() - 1)])
+        .format_timestamp(None)
+        .format_target(false)
+        .parse_default_env()
+        .init();
+}
+
+/// Run the command, noting the device it runs on in `device`.
+fn run(cli: Cli, device: &mut Option<DeviceReport>) -> std::result::Result<(), Error> {
+    let cmd = cli.cmd;
+    let mut settings = Settings {
+        set_len: !cli.no_data_length,
+        probe: !cli.no_dev_info,
+        rom_check: !cli.no_rom_check,
+        pre_load: cli.pre_load_command,
+        post_run: cli.post_run_command,
+        deadline: None,
+        rom_base: cli.rom_base,
+        backoff: Backoff {
+            period: Duration::from_millis(cli.wait_backoff_ms),
+            retries: cli.wait_retries,
+            ..Backoff::default()
+        },
+    };
+    let claim_timeout = Duration::from_millis(cli.claim_timeout);
+
+    if let Command::Poke { value, width, .. } = &cmd {
+        let bits = width.bytes() * 8;
+        if bits < 32 && value >> bits != 0 {
+            let msg = format!("value {value:#x} does not fit in {bits} bits");
+            Cli::command().error(ErrorKind::InvalidValue, msg).exit();
+        }
+    }
+    if matches!(cmd, Command::Watch { .. }) && cli.index.is_some() {
+        let msg = "--index cannot be used with watch, which takes every board";
+        Cli::command()
+            .error(ErrorKind::ArgumentConflict, msg)
+            .exit();
+    }
+    if let Command::Load {
+        address,
+        repeat_count,
+        address_stride: Some(stride),
+        ..
+    } = &cmd
+    {
+        let last = *address as u64 + (*repeat_count as u64 - 1) * *stride as u64;
+        if last > u32::MAX as u64 {
+            let msg = format!("copy {repeat_count} would start at {last:#x}, beyond 32 bits");
+            Cli::command().error(ErrorKind::InvalidValue, msg).exit();
+        }
+    }
+
+    if let Command::Monitor {
+        port,
+        baud,
+        timestamps,
+        raw,
+        log,
+    } = cmd
+    {
+        let opts = monitor::Options {
+            timestamps,
+            raw,
+            log,
+        };
+        return monitor::run(&port, baud, &opts);
+    }
+
+    if let Command::Checksum { file_name, algo } = &cmd {
+        let sum = checksum::file(file_name, *algo).map_err(Error::file(file_name))?;
+        outln!("{sum}  {file_name}");
+        return Ok(());
+    }
+
+    if let Command::Completions { shell } = cmd {
+        let mut cli = Cli::command();
+        let name = cli.get_name().to_string();
+        clap_complete::generate(shell, &mut cli, name, &mut data_out());
+        return Ok(());
+    }
+
+    // Catch a mistyped path before waiting for and talking to the device.
+    let inputs = match &cmd {
+        Command::Load { file_name, .. } | Command::Run { file_name, .. } => vec![file_name],
+        Command::Boot { spl, payload, .. } => vec![spl, payload],
+        Command::Watch { file_name, .. } => vec![file_name],
+        Command::LoadMulti { images, .. } => images.iter().map(|i| &i.file_name).collect(),
+        _ => vec![],
+    };
+    for file_name in inputs {
+        check_input(file_name)?;
+    }
+
+    let chips = match cli.chip {
+        Some(c) => vec![c],
+        None => Chip::ALL.to_vec(),
+    };
+    let ids = ChipIds::new(&chips, cli.vid, cli.pid);
+    let sel = Selection {
+        serial: cli.device.as_deref(),
+        port: cli.bus_port.as_deref(),
+        index: cli.index,
+    };
+    if let Command::Devices = cmd {
+        let devices = ids.devices()?;
+        print_devices(&devices);
+        return Ok(());
+    }
+    let open_opts = OpenOptions::new()
+        .with_claim_timeout(claim_timeout)
+        .with_claim_period(Duration::from_micros(cli.claim_period))
+        .with_out_ep(cli.out_ep)
+        .with_in_ep(cli.in_ep)
+        .with_interface(cli.interface)
+        .with_alt_setting(cli.alt_setting)
+        .with_detach_kernel_driver(cli.detach_kernel_driver);
+    if let Command::Watch {
+        file_name,
+        address,
+        verify,
+        force,
+    } = cmd
+    {
+        let flash = Command::Run {
+            address,
+            file_name,
+            verify,
+            force,
+            entry: None,
+            start_delay: 0,
+            drain_ms: None,
+            monitor: None,
+            manifest: None,
+            watch_disconnect: None,
+            baud: 0,
+            repeat: 1,
+        };
+        return watch(&ids, &sel, |di| {
+            let mut target = target_of(&ids, di)?;
+            let (chunk_size, timeout) = transfer_params(cli.chunk_size, cli.timeout, &target);
+            // Each board gets the whole --global-timeout.
+            let settings = Settings {
+                deadline: cli
+                    .global_timeout
+                    .map(|secs| (Instant::now() + Duration::from_secs(secs), secs)),
+                ..settings.clone()
             };
+            let deadline = settings.deadline.map(|(d, _)| d);
+            if cli.dry_run {
+                // The boards are real, but what would be sent to them is
+                // only logged.
+                target.info = None;
+                let k = Kendryte::new(DryRun::new(), 0x01, 0x81)
+                    .with_stop_flag(STOP.clone())
+                    .with_counts(COUNTS.clone())
+                    .with_deadline(deadline)
+                    .with_timeout(timeout)
+                    .with_chunk_size(chunk_size);
+                return execute(&k, flash.clone(), &target, &settings);
+            }
+            let di = target.info.as_ref().expect("boards are real");
+            let k = Kendryte::open_with(di, &open_opts)?
+                .with_stop_flag(STOP.clone())
+                .with_counts(COUNTS.clone())
+                .with_deadline(deadline)
+                .with_timeout(timeout)
+                .with_chunk_size(chunk_size)
+                .with_queue_depth(cli.depth as usize);
+            execute(&k, flash.clone(), &target, &settings)
+        });
+    }
+    let target = match cli.dry_run {
+        // Pretend to have found a high-speed device.
+        true => Target {
+            chip: cli.chip.unwrap_or(Chip::ALL[0]),
+            info: None,
+            link: LinkInfo {
+                speed: Speed::High,
+                max_packet_size: 512,
+            },
+        },
+        false => find_target(&ids, &sel, cli.wait, &settings.backoff)?,
+    };
+    *device = target
+        .info
+        .as_ref()
+        .map(|di| DeviceReport::new(target.chip, di, target.link));
+    if let Command::Describe = cmd {
+        return match &target.info {
+            Some(di) => describe(di),
+            None => {
+                info!("Dry run, no device to describe");
+                Ok(())
+            }
+        };
+    }
+    // Waiting for the device has its own limit with --wait.
+    settings.deadline = cli
+        .global_timeout
+        .map(|secs| (Instant::now() + Duration::from_secs(secs), secs));
+    let deadline = settings.deadline.map(|(d, _)| d);
+    let (chunk_size, timeout) = transfer_params(cli.chunk_size, cli.timeout, &target);
+
+    // Booting is running the SPL, and then the payload once the SPL is up.
+    let (cmd, next) = match cmd {
+        Command::Boot {
+            spl,
+            spl_address,
+            payload,
+            dram_address,
+            delay,
+            reconnect_timeout,
+        } => {
+            let next = run_command(payload, dram_address);
+            (
+                run_command(spl, spl_address),
+                Some((next, delay, reconnect_timeout)),
+            )
+        }
+        cmd => (cmd, None),
+    };
+    let repeat = match cmd {
+        Command::Load { repeat, .. } | Command::Run { repeat, .. } => repeat,
+        _ => 1,
+    };
 
-            block_on(fut.or(async {
-                Timer::after(timeout).await;
-                Err(TimedOut.into())
-            }))
+    let (best_effort, verify_chunks) = match cmd {
+        Command::Load {
+            best_effort,
+            verify_chunk,
+            ..
+        } => (best_effort, verify_chunk),
+        _ => (false, false),
+    };
+    let Some(di) = &target.info else {
+        if let Command::Probe = cmd {
+            info!("Dry run, no device to probe");
+            return Ok(());
+        }
+        let k = Kendryte::new(DryRun::new(), 0x01, 0x81)
+            .with_stop_flag(STOP.clone())
+            .with_counts(COUNTS.clone())
+            .with_deadline(deadline)
+            .with_best_effort(best_effort)
+            .with_verify_chunks(verify_chunks)
+            .with_timeout(timeout)
+            .with_chunk_size(chunk_size)
+            .with_queue_depth(cli.depth as usize);
+        if repeat > 1 {
+            return repeat_command(repeat, |_| execute(&k, cmd.clone(), &target, &settings));
+        }
+        let Some((next, delay, reconnect_timeout)) = next else {
+            return execute(&k, cmd, &target, &settings);
         };
+        let (first, second) = boot_stages(&settings);
+        execute(&k, cmd, &target, &first)?;
+        info!(
+            "Dry run, would wait {delay} ms, and then up to {reconnect_timeout} ms for the SPL \
+             to show up"
+        );
+        return execute(&k, next, &target, &second);
+    };
+    let open = |di: &DeviceInfo| -> std::result::Result<Kendryte, Error> {
+        Ok(Kendryte::open_with(di, &open_opts)?
+            .with_stop_flag(STOP.clone())
+            .with_counts(COUNTS.clone())
+            .with_deadline(deadline)
+            .with_best_effort(best_effort)
+            .with_verify_chunks(verify_chunks)
+            .with_timeout(timeout)
+            .with_chunk_size(chunk_size)
+            .with_queue_depth(cli.depth as usize))
+    };
+    if let Command::Probe = cmd {
+        open(di)?;
+        outln!("{}", device_line(di));
+        return Ok(());
+    }
+    if repeat > 1 {
+        // After running code, or a failure, the device has to be found and
+        // claimed again.
+        let reopen = matches!(cmd, Command::Run { .. });
+        let mut claimed = None;
+        return repeat_command(repeat, |i| {
+            let k = match claimed.take() {
+                Some(k) => k,
+                None if i == 0 => open(di)?,
+                None => open(&wait_device(&ids, &sel, REPEAT_WAIT, &settings.backoff)?)?,
+            };
+            execute(&k, cmd.clone(), &target, &settings)?;
+            if !reopen {
+                claimed = Some(k);
+            }
+            Ok(())
+        });
+    }
+    let k = open(di)?;
+    let Some((next, delay, reconnect_timeout)) = next else {
+        return execute(&k, cmd, &target, &settings);
+    };
+    let (first, second) = boot_stages(&settings);
+    execute(&k, cmd, &target, &first)?;
+    drop(k);
+    thread::sleep(Duration::from_millis(delay));
+    let d = wait_reconnect(di, reconnect_timeout, &settings.backoff)?;
+    info!("SPL is up: {}", device_line(&d));
+    let k = open(&d)?;
+    let target = Target {
+        info: Some(d),
+        ..target
+    };
+    execute(&k, next, &target, &second)
+}
+
+/// Settings for the SPL and the payload of `boot`. The hooks go around the
+/// whole boot, and the payload goes to the SPL, not the mask ROM.
+fn boot_stages(settings: &Settings) -> (Settings, Settings) {
+    let first = Settings {
+        post_run: None,
+        ..settings.clone()
+    };
+    let second = Settings {
+        rom_check: false,
+        pre_load: None,
+        ..settings.clone()
+    };
+    (first, second)
+}
+
+/// Run `command` in the shell, with the serial number and port path of the
+/// device in `KENDRYTE_SERIAL` and `KENDRYTE_PORT`, failing if it does.
+fn run_hook(command: &str, target: &Target) -> std::result::Result<(), Error> {
+    let Some(di) = &target.info else {
+        info!("Dry run, not running '{command}'");
+        return Ok(());
+    };
+    info!("Running '{command}'");
+    let (shell, flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let status = process::Command::new(shell)
+        .args([flag, command])
+        .env("KENDRYTE_SERIAL", di.serial_number().unwrap_or_default())
+        .env("KENDRYTE_PORT", port_path(di).unwrap_or_default())
+        .status();
+    let status = match status {
+        Ok(s) if s.success() => return Ok(()),
+        Ok(s) => s.to_string(),
+        Err(e) => e.to_string(),
+    };
+    Err(Error::Hook {
+        command: command.into(),
+        status,
+    })
+}
+
+/// How long to wait for the device between iterations of `--repeat`
+const REPEAT_WAIT: Duration = Duration::from_secs(5);
+
+/// Run `iteration` `n` times, and report how many of them went through and
+/// how long they took.
+fn repeat_command(
+    n: u32,
+    mut iteration: impl FnMut(u32) -> std::result::Result<(), Error>,
+) -> std::result::Result<(), Error> {
+    let mut failed = 0;
+    let mut times = Vec::new();
+    for i in 0..n {
+        info!("Iteration {} of {n}", i + 1);
+        let start = Instant::now();
+        match iteration(i) {
+            Ok(()) => times.push(start.elapsed()),
+            Err(e) if STOP.load(Ordering::SeqCst) => return Err(e),
+            Err(e) => {
+                error!("Iteration {} failed: {e}", i + 1);
+                failed += 1;
+            }
+        }
+    }
+    info!("{} of {n} iterations succeeded", n - failed);
+    if let (Some(min), Some(max)) = (times.iter().min(), times.iter().max()) {
+        let avg = times.iter().sum::<Duration>() / times.len() as u32;
+        info!("Time per iteration: min {min:.2?}, avg {avg:.2?}, max {max:.2?}");
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(Error::Repeat { failed, total: n }),
     }
 }
 
-fn main() {
-    let cmd = Cli::parse().cmd;
+/// Wait up to `timeout` ms for `di` to come back with a new connection.
+fn wait_reconnect(
+    di: &DeviceInfo,
+    timeout: u64,
+    backoff: &Backoff,
+) -> std::result::Result<DeviceInfo, Error> {
+    let id = di.id();
+    let serial = di.serial_number().map(String::from);
+    let timeout = Duration::from_millis(timeout);
+    wait_for_device_with(timeout, backoff, |d| {
+        d.id() != id
+            && (d.vendor_id(), d.product_id()) == (di.vendor_id(), di.product_id())
+            && d.serial_number().map(String::from) == serial
+    })
+    .map_err(Error::transfer("Waiting for device failed"))
+}
+
+/// Report when `di` drops off the bus after running code, which it only does
+/// when the code started, and when it comes back, waiting up to `timeout` ms
+/// for each.
+fn watch_disconnect_of(
+    di: &DeviceInfo,
+    timeout: u64,
+    backoff: &Backoff,
+) -> std::result::Result<(), Error> {
+    let start = Instant::now();
+    let id = di.id();
+    let gone = wait_for_device_gone_with(Duration::from_millis(timeout), backoff, |d| d.id() == id);
+    match gone {
+        Ok(()) => info!("Device disconnected after {:.3?}", start.elapsed()),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            warn!("Device is still connected after {timeout} ms, did the code start?");
+            return Ok(());
+        }
+        Err(e) => return Err(Error::Enumerate(e)),
+    }
+    match wait_reconnect(di, timeout, backoff) {
+        Ok(d) => info!(
+            "Device came back after {:.3?}: {}",
+            start.elapsed(),
+            device_line(&d)
+        ),
+        Err(e) => info!("Device did not come back: {e}"),
+    }
+    Ok(())
+}
+
+/// The device to run commands on, as far as they need to know about it
+struct Target {
+    chip: Chip,
+    /// None on a dry run, where there is no device
+    info: Option<DeviceInfo>,
+    link: LinkInfo,
+}
+
+/// Find the device to use and how to talk to it.
+fn find_target(
+    ids: &ChipIds,
+    sel: &Selection,
+    wait: Option<Option<u64>>,
+    backoff: &Backoff,
+) -> std::result::Result<Target, Error> {
+    let di = match wait {
+        Some(t) => {
+            let t = t.map(Duration::from_millis).unwrap_or(Duration::MAX);
+            wait_device(ids, sel, t, backoff)?
+        }
+        None => find_device(ids, sel)?.ok_or(Error::DeviceNotFound)?,
+    };
+    target_of(ids, di)
+}
 
-    let di = nusb::list_devices()
-        .unwrap()
-        .find(|d| d.vendor_id() == KENDRYTE_VID && d.product_id() == K230D_PID)
-        .expect("Device not found, is it connected and in the right mode?");
-    let ms = di.manufacturer_string().unwrap();
-    let ps = di.product_string().unwrap();
-    println!("Found {ms} {ps}");
+/// The target for `di`, found with `ids`
+fn target_of(ids: &ChipIds, di: DeviceInfo) -> std::result::Result<Target, Error> {
+    let chip = ids.chip(&di).ok_or(Error::DeviceNotFound)?;
+    let ms = di.manufacturer_string().unwrap_or("unknown manufacturer");
+    let ps = di.product_string().unwrap_or("unknown product");
+    info!("Found {chip}: {ms} {ps}");
 
-    // Just use the first interface
-    let ii = di.interfaces().next().unwrap().interface_number();
-    let d = di.open().unwrap();
-    let i = claim_interface(&d, ii).unwrap();
+    let link = LinkInfo::of(&di);
+    info!(
+        "speed {:?} - max packet size: {}",
+        link.speed, link.max_packet_size
+    );
+    Ok(Target {
+        chip,
+        info: Some(di),
+        link,
+    })
+}
 
-    let speed = di.speed().unwrap();
-    let packet_size = match speed {
-        Speed::Full | Speed::Low => 64,
-        Speed::High => 512,
-        Speed::Super | Speed::SuperPlus => 1024,
-        _ => panic!("Unknown USB device speed {speed:?}"),
+/// Chunk size and transfer timeout for `target`, unless given
+fn transfer_params(
+    chunk_size: Option<u32>,
+    timeout: Option<u64>,
+    target: &Target,
+) -> (usize, Duration) {
+    let chunk_size = match chunk_size {
+        Some(c) => c as usize,
+        None => chunk_size_for(target.link.max_packet_size),
+    };
+    debug!("chunk size: {chunk_size}");
+    let timeout = match timeout {
+        Some(t) => Duration::from_millis(t),
+        None => timeout_for(chunk_size, target.link.speed),
     };
-    println!("speed {speed:?} - max packet size: {packet_size}");
+    debug!("transfer timeout: {timeout:?}");
+    (chunk_size, timeout)
+}
+
+/// Print what the started code sends on the bulk IN endpoint for `ms`
+/// milliseconds, as text.
+fn drain(k: &Kendryte<impl Transport>, ms: u64, target: &Target) -> std::result::Result<(), Error> {
+    if target.info.is_none() {
+        info!("Dry run, nothing to read from the code");
+        return Ok(());
+    }
+    let mut out = data_out();
+    let n = k
+        .drain(Duration::from_millis(ms), |b| {
+            let _ = out.write_all(String::from_utf8_lossy(b).as_bytes());
+            let _ = out.flush();
+        })
+        .map_err(Error::transfer("Failed to read from the code"))?;
+    debug!("Received {n} bytes from the code");
+    Ok(())
+}
+
+/// Plain `run` of `file_name` at `address`, as a stage of `boot`
+fn run_command(file_name: String, address: u32) -> Command {
+    Command::Run {
+        address,
+        file_name,
+        verify: false,
+        force: false,
+        entry: None,
+        start_delay: 0,
+        drain_ms: None,
+        monitor: None,
+        manifest: None,
+        watch_disconnect: None,
+        baud: 0,
+        repeat: 1,
+    }
+}
+
+/// Run `flash` on every board matching `ids` and `sel` that connects, one
+/// after the other, until Ctrl-C, and report how many went through.
+fn watch(
+    ids: &ChipIds,
+    sel: &Selection,
+    mut flash: impl FnMut(DeviceInfo) -> std::result::Result<(), Error>,
+) -> std::result::Result<(), Error> {
+    let mut events = nusb::watch_devices().map_err(Error::Enumerate)?;
+    WATCHING.store(true, Ordering::SeqCst);
+    info!("Waiting for boards, press Ctrl-C to stop");
+    let (mut done, mut failed) = (0, 0);
+    while !STOP.load(Ordering::SeqCst) {
+        // Wake up now and then to notice Ctrl-C.
+        let event = block_on(events.next().or(async {
+            Timer::after(WATCH_POLL_PERIOD).await;
+            None
+        }));
+        let Some(HotplugEvent::Connected(di)) = event else {
+            continue;
+        };
+        if ids.chip(&di).is_none() || !sel.matches(&di) {
+            continue;
+        }
+        let serial = di.serial_number().unwrap_or("-").to_string();
+        info!("Board {serial} connected: {}", device_line(&di));
+        match flash(di) {
+            Ok(()) => {
+                done += 1;
+                info!("Board {serial}: done");
+            }
+            Err(e) => {
+                failed += 1;
+                error!("Board {serial}: {e}");
+            }
+        }
+    }
+    WATCHING.store(false, Ordering::SeqCst);
+    info!("{done} board(s) done, {failed} failed");
+    match failed {
+        0 => Ok(()),
+        _ => Err(Error::Repeat {
+            failed,
+            total: done + failed,
+        }),
+    }
+}
 
-    // TODO: Nice error messages when either is not found
-    // We may also hardcode the endpoint to 0x01.
-    let c = d.configurations().next().unwrap();
-    let s = c.interface_alt_settings().next().unwrap();
+/// How often `watch` checks for Ctrl-C while waiting for a board
+const WATCH_POLL_PERIOD: Duration = Duration::from_millis(200);
+
+/// Fail unless the CPU info reply `raw` is the mask ROM of `chip`.
+fn check_rom(chip: Chip, raw: &[u8]) -> std::result::Result<(), Error> {
+    let expected = chip.rom_signature();
+    if raw.starts_with(expected) {
+        return Ok(());
+    }
+    Err(Error::NotInRom {
+        expected: String::from_utf8_lossy(expected).into(),
+        reply: cpu_info_text(raw),
+    })
+}
+
+/// List the ranges that were skipped with `--best-effort`, failing if any.
+fn report_failed(k: &Kendryte<impl Transport>) -> std::result::Result<(), Error> {
+    let failed = k.take_failed();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let mut bytes = 0;
+    for r in &failed {
+        let len = r.end.wrapping_sub(r.start);
+        warn!(
+            "Not loaded: {:#010x}..{:#010x} ({len} bytes)",
+            r.start, r.end
+        );
+        bytes += len as u64;
+    }
+    Err(Error::ChunksFailed {
+        ranges: failed.len(),
+        bytes,
+    })
+}
 
-    let mut es = s.endpoints();
-    let e_out = es.find(|e| e.direction() == Direction::Out).unwrap();
-    let e_out_addr = e_out.address();
+/// Settings from the command line that apply to every command
+#[derive(Clone)]
+struct Settings {
+    /// Announce the data length before loading
+    set_len: bool,
+    /// Read the CPU info before running the command
+    probe: bool,
+    /// Make sure that the mask ROM is answering before loading
+    rom_check: bool,
+    /// When to give up, and the --global-timeout that is from
+    deadline: Option<(Instant, u64)>,
+    rom_base: Option<u32>,
+    /// How to poll while waiting for the device
+    backoff: Backoff,
+    /// Shell commands to run before loading and after running code
+    pre_load: Option<String>,
+    post_run: Option<String>,
+}
 
-    let mut es = s.endpoints();
-    let e_in = es.find(|e| e.direction() == Direction::In).unwrap();
-    let e_in_addr = e_in.address();
+fn execute(
+    k: &Kendryte<impl Transport>,
+    cmd: Command,
+    target: &Target,
+    settings: &Settings,
+) -> std::result::Result<(), Error> {
+    let result = execute_command(k, cmd, target, settings);
+    match (result, settings.deadline) {
+        (Err(e), Some((deadline, secs))) if Instant::now() >= deadline => Err(Error::Deadline {
+            secs,
+            source: Box::new(e),
+        }),
+        (result, _) => result,
+    }
+}
 
-    dev_info(&i);
+fn execute_command(
+    k: &Kendryte<impl Transport>,
+    cmd: Command,
+    target: &Target,
+    settings: &Settings,
+) -> std::result::Result<(), Error> {
+    let chip = target.chip;
+    let set_len = settings.set_len;
+    let rom_base = settings.rom_base.unwrap_or(MASK_ROM_BASE);
+    // Printing the CPU info is all that `cpu-info` does, elsewhere it is
+    // only for the log and not worth failing over.
+    let cpu_info = matches!(cmd, Command::CpuInfo { .. });
+    // Loading to anything but the mask ROM goes nowhere; a dry run has no
+    // ROM to ask.
+    let loads = matches!(
+        cmd,
+        Command::Load { .. }
+            | Command::Run { .. }
+            | Command::LoadMulti { .. }
+            | Command::Fill { .. }
+            | Command::Selftest { .. }
+    );
+    let rom_check = settings.rom_check && target.info.is_some() && loads;
+    // --no-dev-info is for when asking hangs, so it goes for the check too.
+    if rom_check && !settings.probe {
+        warn!("Not checking for the mask ROM, --no-dev-info skips reading the CPU info");
+    }
+    let rom_check = rom_check && settings.probe;
+    let mut reply = None;
+    if cpu_info || rom_check || settings.probe {
+        match k.cpu_info_raw() {
+            Ok(raw) => {
+                info!("Device says: {}", cpu_info_text(&raw));
+                debug!("CPU info bytes: {}", hex_string(&raw));
+                if rom_check {
+                    check_rom(chip, &raw)?;
+                }
+                reply = Some(raw);
+            }
+            Err(e) if cpu_info || rom_check => {
+                return Err(Error::transfer("Could not get CPU info")(e))
+            }
+            Err(e) => warn!("Could not get CPU info: {e}"),
+        }
+    }
+    if let Some(hook) = settings.pre_load.as_deref().filter(|_| loads) {
+        run_hook(hook, target)?;
+    }
 
     match cmd {
-        Command::CpuInfo => {}
-        Command::Rom => run_code(&i, MASK_ROM_BASE as u32),
-        Command::Load { file_name, address } => {
-            let data = File::open(file_name).unwrap();
-            load(&i, e_out_addr, address, &data);
+        Command::CpuInfo {
+            json,
+            expect,
+            raw: as_is,
+        } => {
+            let raw = reply.expect("CPU info is read for cpu-info");
+            if json {
+                cpu_info_json(&raw, target);
+            } else {
+                print_cpu_info(&raw, chip, as_is);
+            }
+            if let Some(expected) = expect {
+                let reply = cpu_info_text(&raw);
+                if !reply.contains(&expected) {
+                    return Err(Error::InfoMismatch { expected, reply });
+                }
+            }
+        }
+        Command::Devices
+        | Command::Watch { .. }
+        | Command::Probe
+        | Command::Describe
+        | Command::Checksum { .. }
+        | Command::Completions { .. }
+        | Command::Monitor { .. }
+        | Command::Boot { .. } => {}
+        Command::Rom => k.jump_to_rom_at(rom_base).map_err(Error::transfer(format!(
+            "Device rejected jump to mask ROM at {rom_base:#010x}"
+        )))?,
+        Command::Load {
+            file_name,
+            address,
+            verify,
+            force,
+            skip,
+            length,
+            align,
+            append_length,
+            append_crc32,
+            repeat_count,
+            address_stride,
+            manifest,
+            pad_to,
+            pad_byte,
+            ..
+        } => {
+            let opts = LoadOptions {
+                chip,
+                set_len,
+                verify,
+                force,
+                skip,
+                length,
+                align,
+                pad_to,
+                pad_byte,
+                trailer: Trailer {
+                    length: append_length,
+                    crc32: append_crc32,
+                },
+                symbol: None,
+                digest: manifest.as_ref().map(|_| InputDigest::new()),
+            };
+            let sent = COUNTS.sent();
+            let loaded = (|| {
+                // Checked to fit in 32 bits up front.
+                let stride = address_stride.unwrap_or(0);
+                for i in 0..repeat_count {
+                    let at = address + i * stride;
+                    if repeat_count > 1 {
+                        info!("Loading copy {} of {repeat_count} to {at:#010x}", i + 1);
+                    }
+                    load_file(k, at, &file_name, &opts)?;
+                }
+                report_failed(k)
+            })();
+            if let Some(path) = &manifest {
+                let bytes = COUNTS.sent() - sent;
+                write_manifest(path, &file_name, &opts, bytes, target, &loaded)?;
+            }
+            loaded?;
+        }
+        Command::Run {
+            file_name,
+            address,
+            verify,
+            force,
+            entry,
+            start_delay,
+            drain_ms,
+            monitor,
+            baud,
+            watch_disconnect,
+            manifest,
+            ..
+        } => {
+            let symbol = match &entry {
+                Some(Entry::Symbol(name)) => Some(name.clone()),
+                _ => None,
+            };
+            let opts = LoadOptions {
+                chip,
+                set_len,
+                verify,
+                force,
+                skip: 0,
+                length: None,
+                align: 1,
+                pad_to: None,
+                pad_byte: 0,
+                trailer: Trailer::default(),
+                symbol,
+                digest: manifest.as_ref().map(|_| InputDigest::new()),
+            };
+            let sent = COUNTS.sent();
+            let loaded = load_file(k, address, &file_name, &opts);
+            if let Some(path) = &manifest {
+                let bytes = COUNTS.sent() - sent;
+                write_manifest(path, &file_name, &opts, bytes, target, &loaded)?;
+            }
+            let (entry, from) = match (entry, loaded?) {
+                (Some(Entry::Address(a)), _) => (a, "--entry".into()),
+                (Some(Entry::Symbol(name)), Some(a)) => (a, format!("symbol {name}")),
+                (None, Some(a)) => (a, "entry point of the image".into()),
+                (_, None) => (address, "load address".into()),
+            };
+            // Freshly loaded code may still be sitting in the data cache.
+            k.flush_caches()
+                .map_err(Error::transfer("Failed to flush caches"))?;
+            if start_delay > 0 {
+                debug!("Waiting {start_delay} ms before the jump");
+                thread::sleep(Duration::from_millis(start_delay));
+            }
+            info!("Jumping to {entry:#010x} ({from})");
+            k.run(entry).map_err(Error::transfer(format!(
+                "Device rejected jump to {entry:#010x}"
+            )))?;
+            if let Some(hook) = &settings.post_run {
+                run_hook(hook, target)?;
+            }
+            if let Some(ms) = drain_ms {
+                drain(k, ms, target)?;
+            }
+            if let Some(port) = monitor {
+                monitor::run(&port, baud, &monitor::Options::default())?;
+            }
+            if let Some(timeout) = watch_disconnect {
+                match &target.info {
+                    Some(di) => watch_disconnect_of(di, timeout, &settings.backoff)?,
+                    None => info!("Dry run, not watching the device"),
+                }
+            }
+        }
+        Command::LoadMulti {
+            images,
+            run,
+            verify,
+            force,
+        } => {
+            let opts = LoadOptions {
+                chip,
+                set_len,
+                verify,
+                force,
+                skip: 0,
+                length: None,
+                align: 1,
+                pad_to: None,
+                pad_byte: 0,
+                trailer: Trailer::default(),
+                symbol: None,
+                digest: None,
+            };
+            for image in &images {
+                info!("Loading {} to {:#010x}", image.file_name, image.address);
+                load_file(k, image.address, &image.file_name, &opts)?;
+            }
+            if let Some(entry) = run {
+                k.flush_caches()
+                    .map_err(Error::transfer("Failed to flush caches"))?;
+                info!("Jumping to {entry:#010x}");
+                k.run(entry).map_err(Error::transfer(format!(
+                    "Device rejected jump to {entry:#010x}"
+                )))?;
+                if let Some(hook) = &settings.post_run {
+                    run_hook(hook, target)?;
+                }
+            }
         }
-        Command::Run { file_name, address } => {
-            let data = File::open(file_name).unwrap();
-            load(&i, e_out_addr, address, &data);
-            run_code(&i, address);
+        Command::Reset { reconnect_timeout } => {
+            k.jump_to_rom_at(rom_base).map_err(Error::transfer(format!(
+                "Device rejected jump to mask ROM at {rom_base:#010x}"
+            )))?;
+            let Some(di) = &target.info else {
+                info!("Dry run, not waiting for the device to come back");
+                return Ok(());
+            };
+            let d = wait_reconnect(di, reconnect_timeout, &settings.backoff)?;
+            info!("Device is back: {}", device_line(&d));
         }
+        Command::Flush => k
+            .flush_caches()
+            .map_err(Error::transfer("Failed to flush caches"))?,
+        Command::Dump {
+            address,
+            length,
+            file_name,
+            format,
+            raw,
+            words,
+            endian,
+        } => {
+            // Ending right at the top is fine, the last read starts below it.
+            if address as u64 + length as u64 > 1 << 32 {
+                return Err(Error::InvalidRegion {
+                    op: "dump",
+                    addr: address,
+                    len: length.into(),
+                    reason: "it exceeds the 32-bit address space",
+                });
+            }
+            let words = words.map(|w| (w, endian));
+            let format = match (format, raw) {
+                (_, true) => DumpFormat::Binary,
+                (Some(f), _) => f,
+                (None, _) if file_name.is_some() => DumpFormat::Binary,
+                (None, _) => DumpFormat::Hex,
+            };
+            match file_name {
+                Some(f) => {
+                    let mut out = File::create(&f).map_err(Error::file(&f))?;
+                    dump(k, address, length, &mut out, &f, format, words)?;
+                }
+                None => {
+                    let name = match JSON_OUTPUT.load(Ordering::Relaxed) {
+                        true => "stderr",
+                        false => "stdout",
+                    };
+                    dump(k, address, length, &mut data_out(), name, format, words)?;
+                }
+            }
+        }
+        Command::Fill {
+            address,
+            length,
+            value,
+            pattern,
+        } => {
+            let pattern = pattern.map_or_else(|| value.into_iter().collect(), |p| p.0);
+            let mut progress = Progress::new(Some(length as u64));
+            stoppable(k, || {
+                k.fill(address, length, &pattern, |b| progress.update(b.len()))
+            })
+            .map_err(Error::transfer(format!(
+                "Failed to fill at {address:#010x}"
+            )))?;
+            progress.finish(Duration::ZERO);
+        }
+        Command::Selftest { address, length } => {
+            let address = address.unwrap_or_else(|| chip.scratch());
+            if target.info.is_none() {
+                info!("Dry run, no device to test");
+                return Ok(());
+            }
+            validate_load_region(chip, address, length as u64)?;
+            let t = stoppable(k, || k.self_test(address, length)).map_err(load_error(
+                "test pattern",
+                format!("Self-test at {address:#010x} failed"),
+            ))?;
+            if QUIET.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            outln!(
+                "PASS: {length:#x} bytes at {address:#010x}, written in {:.3}s, read back in {:.3}s",
+                t.write.as_secs_f64(),
+                t.read.as_secs_f64()
+            );
+        }
+        Command::Interactive => {
+            let opts = LoadOptions {
+                chip,
+                set_len,
+                verify: false,
+                force: false,
+                skip: 0,
+                length: None,
+                align: 1,
+                pad_to: None,
+                pad_byte: 0,
+                trailer: Trailer::default(),
+                symbol: None,
+                digest: None,
+            };
+            interactive(k, &opts).map_err(Error::file("stdin"))?;
+        }
+        Command::Ctrl {
+            request,
+            value,
+            index,
+            input,
+            length,
+            ..
+        } => {
+            let val = (value as u32) << 16 | index as u32;
+            let context = format!("Control request {request:#04x} failed");
+            if input {
+                let mut buf = vec![0_u8; length as usize];
+                let n = k
+                    .cmd_in(&mut buf, request, val)
+                    .map_err(Error::transfer(context))?;
+                info!("Received {n} bytes");
+                print_hex(&mut data_out(), 0, &buf[..n], None).map_err(Error::file("stdout"))?;
+            } else {
+                k.cmd_out(request, val).map_err(Error::transfer(context))?;
+            }
+        }
+        Command::Peek {
+            address,
+            width,
+            endian,
+        } => {
+            let mut buf = vec![0_u8; width.bytes()];
+            k.read(address, &mut buf).map_err(Error::transfer(format!(
+                "Failed to read from {address:#010x}"
+            )))?;
+            let digits = width.bytes() * 2;
+            outln!(
+                "{address:#010x}: {:#0w$x}",
+                endian.decode(&buf),
+                w = digits + 2
+            );
+        }
+        Command::Poke {
+            address,
+            value,
+            width,
+            endian,
+        } => {
+            let bytes = endian.encode(value, width);
+            k.load(address, &bytes[..])
+                .map_err(Error::transfer(format!(
+                    "Failed to write to {address:#010x}"
+                )))?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let config = Config::find();
+    let mut cmd = Cli::command();
+    if let Ok(Some((_, c))) = &config {
+        cmd = c.apply(cmd);
+    }
+    let args = cli::expand_arg_files(std::env::args_os())
+        .unwrap_or_else(|e| cmd.error(ErrorKind::Io, e).exit());
+    let matches = cmd.get_matches_from(args);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    init_logger(cli.verbose, cli.quiet);
+    QUIET.store(cli.quiet > 0, Ordering::Relaxed);
+    if let Err(e) = ctrlc::set_handler(on_ctrl_c) {
+        debug!("cannot handle Ctrl-C: {e}");
+    }
+    let json = cli.output_format == OutputFormat::Json;
+    JSON_OUTPUT.store(json, Ordering::Relaxed);
+
+    let start = Instant::now();
+    let mut device = None;
+    let result = config.and_then(|config| {
+        if let Some((path, _)) = config {
+            debug!("Using defaults from {}", path.display());
+        }
+        run(cli, &mut device)
+    });
+    let code = match &result {
+        Ok(()) => 0,
+        Err(e) => {
+            // -qq silences errors in either format.
+            match json {
+                true if log_enabled!(Level::Error) => print_error_json(e),
+                true => {}
+                false => error!("{e}"),
+            }
+            match STOP.load(Ordering::SeqCst) {
+                true => EXIT_INTERRUPTED,
+                false => exit_code(e),
+            }
+        }
+    };
+    if json {
+        let report = CommandResult {
+            command: matches.subcommand_name().unwrap_or_default().into(),
+            success: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+            exit_code: code,
+            bytes_out: COUNTS.sent(),
+            bytes_in: COUNTS.received(),
+            duration_ms: start.elapsed().as_millis() as u64,
+            device,
+        };
+        let json = serde_json::to_string(&report).expect("report serializes");
+        println!("{json}");
     }
+    ExitCode::from(code)
 }
@@ -0,0 +1,111 @@
+//! Serial console output of the code that was started
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kendryte_boot::Error;
+use log::{debug, info};
+
+/// How long the port may take to show up after the code starts
+const OPEN_TIMEOUT: Duration = Duration::from_secs(2);
+const OPEN_PERIOD: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How to show what comes in
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Prefix each line with the seconds since the start
+    pub timestamps: bool,
+    /// Pass on bytes as they come instead of whole lines
+    pub raw: bool,
+    /// Also write everything to this file
+    pub log: Option<String>,
+}
+
+/// Writes to stdout and the log, adding timestamps at line starts
+struct Console {
+    log: Option<File>,
+    timestamps: bool,
+    start: Instant,
+    line_start: bool,
+}
+
+impl Console {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(data.len());
+        for &b in data {
+            if self.line_start && self.timestamps {
+                let t = self.start.elapsed().as_secs_f64();
+                buf.extend_from_slice(format!("[{t:10.3}] ").as_bytes());
+            }
+            buf.push(b);
+            self.line_start = b == b'\n';
+        }
+        let mut out = io::stdout();
+        out.write_all(&buf)?;
+        out.flush()?;
+        if let Some(log) = &mut self.log {
+            log.write_all(&buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy everything from `port` to stdout until interrupted.
+pub fn run(port: &str, baud: u32, opts: &Options) -> Result<(), Error> {
+    let serial = |source| Error::Serial {
+        port: port.into(),
+        source,
+    };
+    let log = match &opts.log {
+        Some(path) => Some(File::create(path).map_err(Error::file(path))?),
+        None => None,
+    };
+    let mut p = open(port, baud).map_err(serial)?;
+    info!("Monitoring {port} at {baud} baud, Ctrl-C to quit");
+    let mut console = Console {
+        log,
+        timestamps: opts.timestamps,
+        start: Instant::now(),
+        line_start: true,
+    };
+    let mut buf = [0_u8; 1024];
+    // Whole lines are passed on, and what is left once the port goes quiet,
+    // so that prompts show up.
+    let mut pending = Vec::new();
+    loop {
+        match p.read(&mut buf) {
+            Ok(n) if opts.raw => console.write(&buf[..n]).map_err(serial)?,
+            Ok(n) => {
+                pending.extend_from_slice(&buf[..n]);
+                if let Some(end) = pending.iter().rposition(|&b| b == b'\n') {
+                    console.write(&pending[..=end]).map_err(serial)?;
+                    pending.drain(..=end);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                console.write(&pending).map_err(serial)?;
+                pending.clear();
+            }
+            Err(e) => return Err(serial(e)),
+        }
+    }
+}
+
+/// Open `port`, retrying while it does not exist yet.
+fn open(port: &str, baud: u32) -> io::Result<Box<dyn serialport::SerialPort>> {
+    let start = Instant::now();
+    loop {
+        let e = match serialport::new(port, baud).timeout(READ_TIMEOUT).open() {
+            Ok(p) => return Ok(p),
+            Err(e) => io::Error::from(e),
+        };
+        if e.kind() != ErrorKind::NotFound || start.elapsed() >= OPEN_TIMEOUT {
+            return Err(e);
+        }
+        debug!("opening {port} failed, retrying: {e}");
+        thread::sleep(OPEN_PERIOD);
+    }
+}
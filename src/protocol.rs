@@ -0,0 +1,848 @@
+//! The mask ROM's vendor requests on EP0 and its bulk data endpoints
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Result};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, trace};
+use nusb::transfer::{Queue, TransferError};
+use nusb::{Interface, Speed};
+
+use crate::image::Image;
+use crate::transport::{block_on_timeout, Transport};
+use crate::usb::LinkInfo;
+use crate::MASK_ROM_BASE;
+
+pub const EP0_GET_CPU_INFO: u8 = 0x0;
+pub const EP0_SET_DATA_ADDRESS: u8 = 0x1;
+pub const EP0_SET_DATA_LENGTH: u8 = 0x2;
+pub const EP0_FLUSH_CACHES: u8 = 0x3;
+pub const EP0_PROG_START: u8 = 0x4;
+/// CRC32 of a loaded region, computed on the device
+///
+/// TODO: No such request is known for the K230 mask ROM. Candidates can be
+/// tried with `ctrl --in`; once one is found, set it here and
+/// [`Kendryte::verify`] uses it instead of reading all data back.
+pub const EP0_CHECKSUM: Option<u8> = None;
+
+pub const CHUNK_SIZE: usize = 512;
+
+/// Bulk transfer size for a given max packet size, at least [`CHUNK_SIZE`]
+pub fn chunk_size_for(max_packet_size: usize) -> usize {
+    max_packet_size.max(CHUNK_SIZE)
+}
+
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+/// What [`timeout_for`] allows on top of the time for the data itself
+pub const TRANSFER_TIMEOUT_SLACK: Duration = Duration::from_secs(2);
+
+/// Timeout for transfers of up to `chunk_size` bytes at `speed`: how long
+/// they take at a fraction of what the bus can do, plus some slack
+pub fn timeout_for(chunk_size: usize, speed: Speed) -> Duration {
+    let bytes_per_sec = match speed {
+        Speed::Low | Speed::Full => 100_000,
+        Speed::High => 1_000_000,
+        _ => 10_000_000,
+    };
+    TRANSFER_TIMEOUT_SLACK + Duration::from_secs_f64(chunk_size as f64 / bytes_per_sec as f64)
+}
+
+/// How often to try sending a bulk chunk before giving up
+pub const BULK_OUT_ATTEMPTS: u32 = 3;
+/// Pause after a failed bulk chunk, growing with each attempt
+pub const BULK_OUT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Endless reader cycling through a pattern, like [`io::repeat`] for more
+/// than one byte
+struct Repeat<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+}
+
+impl Read for Repeat<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        for b in buf.iter_mut() {
+            *b = self.pattern[self.pos];
+            self.pos = (self.pos + 1) % self.pattern.len();
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Readable text of a CPU info reply. The reply comes in a fixed size buffer
+/// padded with NUL bytes, which are dropped, and some firmware packs binary
+/// fields into it, which show up as replacement characters.
+pub fn cpu_info_text(raw: &[u8]) -> String {
+    let end = raw.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    String::from_utf8_lossy(&raw[..end]).into_owned()
+}
+
+/// A CPU info reply taken apart, see [`Chip::decode_cpu_info`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuInfo {
+    /// The printable ASCII the reply starts with, e.g. `K230`
+    pub text: String,
+    /// Binary fields after the text, without the NUL padding
+    pub data: Vec<u8>,
+}
+
+impl CpuInfo {
+    /// Split a reply into its printable prefix and the binary rest.
+    pub fn split(raw: &[u8]) -> CpuInfo {
+        let printable = raw
+            .iter()
+            .position(|&b| !(b.is_ascii_graphic() || b == b' '))
+            .unwrap_or(raw.len());
+        let end = raw.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        let (text, rest) = raw.split_at(printable);
+        CpuInfo {
+            text: String::from_utf8_lossy(text).trim_end().into(),
+            data: rest[..end.saturating_sub(printable)].to_vec(),
+        }
+    }
+}
+
+/// Bytes moved to and from the device, see [`Kendryte::with_counts`]
+#[derive(Debug, Default)]
+pub struct TransferCounts {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl TransferCounts {
+    /// Bulk OUT data the device took
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Bulk IN and control IN data that came in
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    fn add_sent(&self, n: usize) {
+        self.sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn add_received(&self, n: usize) {
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// A Kendryte SoC in mask ROM USB loader mode
+pub struct Kendryte<T: Transport = Interface> {
+    transport: T,
+    out_ep: u8,
+    in_ep: u8,
+    timeout: Duration,
+    chunk_size: usize,
+    queue_depth: usize,
+    stop: Option<Arc<AtomicBool>>,
+    deadline: Option<Instant>,
+    best_effort: bool,
+    verify_chunks: bool,
+    /// Device address ranges of chunks skipped with `best_effort`
+    failed: Mutex<Vec<Range<u32>>>,
+    link: Option<LinkInfo>,
+    counts: Arc<TransferCounts>,
+}
+
+impl<T: Transport> Kendryte<T> {
+    /// Wrap an already claimed interface, or another transport, and its bulk
+    /// endpoints.
+    pub fn new(transport: T, out_ep: u8, in_ep: u8) -> Self {
+        Self {
+            transport,
+            out_ep,
+            in_ep,
+            timeout: TRANSFER_TIMEOUT,
+            chunk_size: CHUNK_SIZE,
+            queue_depth: 1,
+            stop: None,
+            deadline: None,
+            best_effort: false,
+            verify_chunks: false,
+            failed: Mutex::default(),
+            link: None,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Remember how the device is connected, for [`Kendryte::link_info`].
+    pub fn with_link_info(mut self, link: LinkInfo) -> Self {
+        self.link = Some(link);
+        self
+    }
+
+    /// Set the timeout for each single USB transfer.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the size of each bulk transfer when loading.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Set how many bulk transfers to keep in flight when loading. With
+    /// more than one, failed transfers are not retried.
+    pub fn with_queue_depth(mut self, depth: usize) -> Self {
+        self.queue_depth = depth.max(1);
+        self
+    }
+
+    /// Stop loading between bulk transfers once `flag` is set, failing with
+    /// [`ErrorKind::Interrupted`] and a [`Cancelled`] inside. Transfers
+    /// already in flight are waited for. To cancel a single load, see
+    /// [`Kendryte::load_cancellable`].
+    pub fn with_stop_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.stop = Some(flag);
+        self
+    }
+
+    /// Add up the bytes transferred in `counts`, which can be shared with
+    /// other instances, e.g. when the device is opened again after running
+    /// code.
+    pub fn with_counts(mut self, counts: Arc<TransferCounts>) -> Self {
+        self.counts = counts;
+        self
+    }
+
+    /// The bytes transferred so far, see [`Kendryte::with_counts`]
+    pub fn counts(&self) -> &TransferCounts {
+        &self.counts
+    }
+
+    /// Fail transfers with [`ErrorKind::TimedOut`] once `deadline` has
+    /// passed, and cut their timeout short to not end after it.
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Skip chunks that still fail after retries instead of aborting the
+    /// load, and go on after them; see [`Kendryte::take_failed`]. This
+    /// loads one chunk at a time whatever the queue depth.
+    pub fn with_best_effort(mut self, best_effort: bool) -> Self {
+        self.best_effort = best_effort;
+        self
+    }
+
+    /// Read each chunk back right after sending it, failing with
+    /// [`ErrorKind::InvalidData`] at the first byte that differs. This
+    /// loads one chunk at a time whatever the queue depth.
+    pub fn with_verify_chunks(mut self, verify_chunks: bool) -> Self {
+        self.verify_chunks = verify_chunks;
+        self
+    }
+
+    /// The device address ranges that were skipped since the last call,
+    /// with adjacent chunks merged
+    pub fn take_failed(&self) -> Vec<Range<u32>> {
+        std::mem::take(&mut self.failed.lock().unwrap())
+    }
+
+    /// Note `len` bytes at `at` as skipped, and point the device past them
+    /// since it is not known how far the failed transfer went.
+    fn skip_failed(&self, at: u32, len: usize, e: io::Error) -> Result<()> {
+        debug!("skipping {len} bytes at {at:#010x}: {e}");
+        let end = at.wrapping_add(len as u32);
+        let mut failed = self.failed.lock().unwrap();
+        match failed.last_mut() {
+            Some(last) if last.end == at => last.end = end,
+            _ => failed.push(at..end),
+        }
+        drop(failed);
+        self.set_code_addr(end)
+    }
+
+    /// The timeout for the next transfer
+    fn transfer_timeout(&self) -> Result<Duration> {
+        let Some(deadline) = self.deadline else {
+            return Ok(self.timeout);
+        };
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(self.timeout.min(left)),
+            _ => Err(io::Error::new(ErrorKind::TimedOut, "deadline has passed")),
+        }
+    }
+
+    fn stopped(&self, sent: u64, cancel: Option<&AtomicBool>) -> Result<()> {
+        let set = |f: Option<&AtomicBool>| f.is_some_and(|f| f.load(Ordering::SeqCst));
+        match set(self.stop.as_deref()) || set(cancel) {
+            true => Err(io::Error::new(ErrorKind::Interrupted, Cancelled { sent })),
+            false => Ok(()),
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Bulk OUT and IN endpoint addresses
+    pub fn endpoints(&self) -> (u8, u8) {
+        (self.out_ep, self.in_ep)
+    }
+
+    /// Speed and max packet size of the device, if it is one; set by
+    /// [`Kendryte::open`], not for other transports
+    pub fn link_info(&self) -> Option<LinkInfo> {
+        self.link
+    }
+
+    /// Issue a vendor control-in request, returning the number of bytes read.
+    pub fn cmd_in(&self, buf: &mut [u8], request: u8, val: u32) -> Result<usize> {
+        let value = (val >> 16) as u16;
+        let index = val as u16;
+        let length = buf.len() as u16;
+        trace!("control in: request {request:#04x} value {value:#06x} index {index:#06x} length {length}");
+        let n = self
+            .transport
+            .control_in(request, value, index, buf, self.transfer_timeout()?)?;
+        self.counts.add_received(n);
+        Ok(n)
+    }
+
+    /// Issue a vendor control-out request without data.
+    pub fn cmd_out(&self, request: u8, val: u32) -> Result<()> {
+        let value = (val >> 16) as u16;
+        let index = val as u16;
+        trace!("control out: request {request:#04x} value {value:#06x} index {index:#06x}");
+        self.transport
+            .control_out(request, value, index, self.transfer_timeout()?)
+    }
+
+    /// The CPU info reply bytes as sent by the device, which may be fewer
+    /// than asked for but not none
+    pub fn cpu_info_raw(&self) -> Result<Vec<u8>> {
+        let mut buf = [0; 0x20];
+        let n = self.cmd_in(&mut buf, EP0_GET_CPU_INFO, 0)?;
+        debug!("CPU info: got {n} of {} bytes", buf.len());
+        if n == 0 {
+            let msg = "device returned no CPU info";
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, msg));
+        }
+        Ok(buf[..n].to_vec())
+    }
+
+    /// The CPU info reply as text, see [`cpu_info_text`]
+    pub fn cpu_info(&self) -> Result<String> {
+        Ok(cpu_info_text(&self.cpu_info_raw()?))
+    }
+
+    pub fn set_code_addr(&self, addr: u32) -> Result<()> {
+        self.cmd_out(EP0_SET_DATA_ADDRESS, addr)
+    }
+
+    pub fn set_data_length(&self, len: u32) -> Result<()> {
+        self.cmd_out(EP0_SET_DATA_LENGTH, len)
+    }
+
+    pub fn flush_caches(&self) -> Result<()> {
+        self.cmd_out(EP0_FLUSH_CACHES, 0)
+    }
+
+    pub fn run(&self, addr: u32) -> Result<()> {
+        self.cmd_out(EP0_PROG_START, addr)
+    }
+
+    /// Flush the caches and jump to the mask ROM at [`MASK_ROM_BASE`].
+    pub fn jump_to_rom(&self) -> Result<()> {
+        self.jump_to_rom_at(MASK_ROM_BASE)
+    }
+
+    /// Like [`Kendryte::jump_to_rom`], for a ROM at `base`.
+    pub fn jump_to_rom_at(&self, base: u32) -> Result<()> {
+        // Code that ran before may have left dirty cache lines behind.
+        self.flush_caches()?;
+        self.run(base)
+    }
+
+    fn bulk_out(&self, data: &[u8]) -> Result<()> {
+        trace!(
+            "bulk out: endpoint {:#04x} length {}",
+            self.out_ep,
+            data.len()
+        );
+        self.transport
+            .bulk_out(self.out_ep, data, self.transfer_timeout()?)?;
+        self.counts.add_sent(data.len());
+        Ok(())
+    }
+
+    /// Send one chunk, retrying on transient errors. `addr` and `offset`
+    /// only serve to tell where a transfer failed.
+    fn bulk_out_retry(&self, data: &[u8], addr: u32, offset: u64) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let e = match self.bulk_out(data) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            let status = transfer_status(&e);
+            let gone = status == Some(&TransferError::Disconnected);
+            let late = self.deadline.is_some_and(|d| Instant::now() >= d);
+            if gone || late || attempt >= BULK_OUT_ATTEMPTS {
+                return Err(ChunkError::wrap(addr, offset, e));
+            }
+            debug!("bulk out at offset {offset:#x} failed (attempt {attempt}): {e}");
+            // A halted endpoint stays halted until cleared.
+            if status == Some(&TransferError::Stall) {
+                if let Err(e) = self.transport.clear_halt(self.out_ep) {
+                    debug!("clearing halt failed: {e}");
+                }
+            }
+            thread::sleep(BULK_OUT_BACKOFF * attempt);
+            attempt += 1;
+        }
+    }
+
+    /// Load everything from `reader` to memory at `addr`.
+    pub fn load(&self, addr: u32, reader: impl Read) -> Result<u64> {
+        self.load_with_progress(addr, reader, None, |_| {})
+    }
+
+    /// Load everything from `reader` to memory at `addr`, announcing `size`
+    /// to the device first if known, and calling `progress` with each chunk
+    /// sent. Returns the number of bytes sent.
+    pub fn load_with_progress(
+        &self,
+        addr: u32,
+        reader: impl Read,
+        size: Option<u32>,
+        progress: impl FnMut(&[u8]),
+    ) -> Result<u64> {
+        self.load_inner(addr, reader, size, None, progress)
+    }
+
+    /// Like [`Kendryte::load_with_progress`], but stop between bulk
+    /// transfers once `cancel` is set, e.g. from a Cancel button on another
+    /// thread. That fails with [`ErrorKind::Interrupted`] and a
+    /// [`Cancelled`] inside, telling how far it got.
+    pub fn load_cancellable(
+        &self,
+        addr: u32,
+        reader: impl Read,
+        size: Option<u32>,
+        cancel: &AtomicBool,
+        progress: impl FnMut(&[u8]),
+    ) -> Result<u64> {
+        self.load_inner(addr, reader, size, Some(cancel), progress)
+    }
+
+    fn load_inner(
+        &self,
+        addr: u32,
+        mut reader: impl Read,
+        size: Option<u32>,
+        cancel: Option<&AtomicBool>,
+        mut progress: impl FnMut(&[u8]),
+    ) -> Result<u64> {
+        self.set_code_addr(addr)?;
+        if let Some(len) = size {
+            self.set_data_length(len)?;
+        }
+        if self.queue_depth > 1 && !self.best_effort && !self.verify_chunks {
+            if let Some(queue) = self.transport.bulk_out_queue(self.out_ep) {
+                return self.load_queued(addr, reader, queue, cancel, progress);
+            }
+        }
+        let mut buf = vec![0_u8; self.chunk_size];
+        let mut sent = 0;
+        loop {
+            self.stopped(sent, cancel)?;
+            let len = reader
+                .read(&mut buf[..])
+                .map_err(|e| InputError::wrap(sent, e))?;
+            if len == 0 {
+                break;
+            }
+            match self.bulk_out_retry(&buf[..len], addr, sent) {
+                Ok(()) if self.verify_chunks => self.verify_chunk(addr, sent, &buf[..len])?,
+                Ok(()) => {}
+                Err(e) if self.best_effort => {
+                    self.skip_failed(addr.wrapping_add(sent as u32), len, e)?
+                }
+                Err(e) => return Err(e),
+            }
+            sent += len as u64;
+            progress(&buf[..len]);
+        }
+        Ok(sent)
+    }
+
+    /// Keep up to `queue_depth` bulk transfers in flight. They complete in
+    /// order, but a failed one cannot be retried without the ones after it
+    /// having landed at the wrong address, so any error aborts the load.
+    /// The buffers of completed transfers are reused for the next ones.
+    /// `progress` is called as transfers complete, with a copy of their
+    /// data kept meanwhile, since nusb hands the buffers back empty.
+    fn load_queued(
+        &self,
+        addr: u32,
+        mut reader: impl Read,
+        mut queue: Queue<Vec<u8>>,
+        cancel: Option<&AtomicBool>,
+        mut progress: impl FnMut(&[u8]),
+    ) -> Result<u64> {
+        let mut in_flight = VecDeque::new();
+        let mut spare = Vec::new();
+        let mut spare_copies: Vec<Vec<u8>> = Vec::new();
+        let mut sent = 0;
+        let mut eof = false;
+        let mut stopped = Ok(());
+        loop {
+            while !eof && stopped.is_ok() && queue.pending() < self.queue_depth {
+                stopped = self.stopped(sent, cancel);
+                if stopped.is_err() {
+                    break;
+                }
+                let mut buf: Vec<u8> = spare.pop().unwrap_or_default();
+                buf.resize(self.chunk_size, 0);
+                let len = reader
+                    .read(&mut buf[..])
+                    .map_err(|e| InputError::wrap(sent, e))?;
+                if len == 0 {
+                    eof = true;
+                    break;
+                }
+                buf.truncate(len);
+                trace!(
+                    "bulk out: endpoint {:#04x} length {len} queued",
+                    self.out_ep
+                );
+                let mut copy = spare_copies.pop().unwrap_or_default();
+                copy.clear();
+                copy.extend_from_slice(&buf);
+                queue.submit(buf);
+                in_flight.push_back((sent, copy));
+                sent += len as u64;
+            }
+            let Some((offset, copy)) = in_flight.pop_front() else {
+                break;
+            };
+            let comp = self.transfer_timeout().and_then(|timeout| {
+                block_on_timeout(timeout, async { Ok(queue.next_complete().await) })
+            });
+            let status = comp.and_then(|c| {
+                spare.push(c.data.reuse());
+                Ok(c.status?)
+            });
+            if let Err(e) = status {
+                return Err(ChunkError::wrap(addr, offset, e));
+            }
+            self.counts.add_sent(copy.len());
+            progress(&copy);
+            spare_copies.push(copy);
+        }
+        stopped.map(|_| sent)
+    }
+
+    /// Load all segments of `image`, calling `progress` like
+    /// [`Kendryte::load_with_progress`]. Returns the number of bytes sent.
+    pub fn load_image(
+        &self,
+        image: &Image,
+        set_len: bool,
+        mut progress: impl FnMut(&[u8]),
+    ) -> Result<u64> {
+        let mut sent = 0;
+        for s in &image.segments {
+            let size = set_len.then_some(s.data.len() as u32);
+            sent += self.load_with_progress(s.addr, &s.data[..], size, &mut progress)?;
+        }
+        Ok(sent)
+    }
+
+    /// Load a known pattern of `len` bytes to `addr`, read it back and
+    /// compare, failing with [`ErrorKind::InvalidData`] where it differs.
+    pub fn self_test(&self, addr: u32, len: u32) -> Result<SelfTest> {
+        let pattern = test_pattern(len as usize);
+        let start = Instant::now();
+        self.load_with_progress(addr, &pattern[..], Some(len), |_| {})?;
+        let write = start.elapsed();
+        let start = Instant::now();
+        self.verify_read_back(addr, &pattern[..])?;
+        Ok(SelfTest {
+            write,
+            read: start.elapsed(),
+        })
+    }
+
+    /// Fill `len` bytes of memory at `addr` with `pattern` repeated, calling
+    /// `progress` like [`Kendryte::load_with_progress`].
+    pub fn fill(
+        &self,
+        addr: u32,
+        len: u32,
+        pattern: &[u8],
+        progress: impl FnMut(&[u8]),
+    ) -> Result<u64> {
+        if pattern.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "empty fill pattern",
+            ));
+        }
+        let reader = Repeat { pattern, pos: 0 }.take(len as u64);
+        self.load_with_progress(addr, reader, Some(len), progress)
+    }
+
+    fn read_data(&self, addr: u32, buf: &mut [u8]) -> Result<usize> {
+        self.set_code_addr(addr)?;
+        trace!("bulk in: endpoint {:#04x} length {}", self.in_ep, buf.len());
+        let n = self
+            .transport
+            .bulk_in(self.in_ep, buf, self.transfer_timeout()?)?;
+        self.counts.add_received(n);
+        Ok(n)
+    }
+
+    /// Pass whatever comes in on the bulk IN endpoint for `duration` to
+    /// `output`, e.g. what freshly started code prints before the device
+    /// goes away. The device going away ends it early. Returns the number
+    /// of bytes received.
+    pub fn drain(&self, duration: Duration, mut output: impl FnMut(&[u8])) -> Result<u64> {
+        let end = Instant::now() + duration;
+        let mut buf = vec![0; self.chunk_size];
+        let mut total = 0;
+        loop {
+            let left = end.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(total);
+            }
+            match self.transport.bulk_in(self.in_ep, &mut buf, left) {
+                Ok(n) => {
+                    self.counts.add_received(n);
+                    output(&buf[..n]);
+                    total += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut => return Ok(total),
+                Err(e) if transfer_status(&e) == Some(&TransferError::Disconnected) => {
+                    debug!("device went away after {total} bytes");
+                    return Ok(total);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Fill all of `buf` with device memory starting at `addr`.
+    pub fn read(&self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        check_region(addr, buf.len() as u64)?;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let a = addr.wrapping_add(offset as u32);
+            let n = self.read_data(a, &mut buf[offset..])?;
+            if n == 0 {
+                let msg = format!("device returned no data at {a:#010x}");
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, msg));
+            }
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// CRC32 of `len` bytes at `addr` as computed by the device, if the ROM
+    /// can do that, see [`EP0_CHECKSUM`]
+    pub fn device_checksum(&self, addr: u32, len: u32) -> Result<u32> {
+        let Some(request) = EP0_CHECKSUM else {
+            let msg = "no checksum request known for the mask ROM";
+            return Err(io::Error::new(ErrorKind::Unsupported, msg));
+        };
+        self.set_code_addr(addr)?;
+        self.set_data_length(len)?;
+        let mut buf = [0; 4];
+        let n = self.cmd_in(&mut buf, request, 0)?;
+        if n < buf.len() {
+            let msg = format!("checksum reply has {n} bytes, expected {}", buf.len());
+            return Err(io::Error::new(ErrorKind::InvalidData, msg));
+        }
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Compare device memory at `addr` against everything from `reader`,
+    /// by checksum if the device can compute one, else by reading it back.
+    pub fn verify(&self, addr: u32, mut reader: impl Read) -> Result<()> {
+        if EP0_CHECKSUM.is_none() {
+            return self.verify_read_back(addr, reader);
+        }
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| InputError::wrap(data.len() as u64, e))?;
+        let crc = crc32fast::hash(&data);
+        match self.device_checksum(addr, data.len() as u32) {
+            Ok(c) if c == crc => return Ok(()),
+            Ok(c) => debug!("device checksum {c:08x}, expected {crc:08x}, reading back"),
+            Err(e) => debug!("device checksum failed, reading back: {e}"),
+        }
+        // Also tells where the data differs.
+        self.verify_read_back(addr, &data[..])
+    }
+
+    /// Compare device memory at `addr` against everything from `reader` by
+    /// reading it back.
+    pub fn verify_read_back(&self, addr: u32, mut reader: impl Read) -> Result<()> {
+        let mut expected = [0_u8; CHUNK_SIZE];
+        let mut actual = [0_u8; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let len = reader
+                .read(&mut expected[..])
+                .map_err(|e| InputError::wrap(offset, e))?;
+            if len == 0 {
+                break;
+            }
+            check_region(addr, offset + len as u64)?;
+            self.read(addr.wrapping_add(offset as u32), &mut actual[..len])?;
+            compare(addr, offset, &expected[..len], &actual[..len])?;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Read back the chunk just sent to `addr + offset`, and point the
+    /// device after it again for the next one.
+    fn verify_chunk(&self, addr: u32, offset: u64, data: &[u8]) -> Result<()> {
+        let at = addr.wrapping_add(offset as u32);
+        let mut actual = vec![0; data.len()];
+        self.read(at, &mut actual)?;
+        compare(addr, offset, data, &actual)?;
+        self.set_code_addr(at.wrapping_add(data.len() as u32))
+    }
+}
+
+/// A chunk that could not be sent, as inner error of the [`io::Error`]
+/// that loading fails with
+#[derive(Debug, thiserror::Error)]
+#[error("transfer failed at offset {offset:#x} ({addr:#010x}): {source}")]
+pub struct ChunkError {
+    /// Into the data being loaded
+    pub offset: u64,
+    /// On the device
+    pub addr: u64,
+    pub source: io::Error,
+}
+
+impl ChunkError {
+    /// Wrap `e` for the chunk at `offset` of data loaded to `addr`, keeping
+    /// its kind.
+    fn wrap(addr: u32, offset: u64, e: io::Error) -> io::Error {
+        let kind = e.kind();
+        let addr = addr as u64 + offset;
+        io::Error::new(
+            kind,
+            ChunkError {
+                offset,
+                addr,
+                source: e,
+            },
+        )
+    }
+}
+
+/// Device memory that read back different from what was loaded, as inner
+/// error of the [`io::Error`] that verifying fails with
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "mismatch at offset {offset:#x} ({addr:#010x}): expected {expected:#04x}, got {actual:#04x}"
+)]
+pub struct Mismatch {
+    /// Into the data being verified
+    pub offset: u64,
+    /// On the device
+    pub addr: u32,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+/// The data to load or verify could not be read, as inner error of the
+/// [`io::Error`] that loading or verifying fails with, to tell it apart
+/// from the device failing
+#[derive(Debug, thiserror::Error)]
+#[error("cannot read data at offset {offset:#x}: {source}")]
+pub struct InputError {
+    /// Into the data, where reading failed
+    pub offset: u64,
+    pub source: io::Error,
+}
+
+impl InputError {
+    /// Wrap `e` from reading at `offset`, keeping its kind.
+    fn wrap(offset: u64, e: io::Error) -> io::Error {
+        io::Error::new(e.kind(), InputError { offset, source: e })
+    }
+}
+
+/// Loading was stopped with [`Kendryte::with_stop_flag`] or
+/// [`Kendryte::load_cancellable`], as inner error of the [`io::Error`] that
+/// it fails with
+#[derive(Debug, thiserror::Error)]
+#[error("stopped after {sent:#x} bytes")]
+pub struct Cancelled {
+    /// Bytes sent before stopping, all of which have landed
+    pub sent: u64,
+}
+
+/// Fail with [`ErrorKind::InvalidInput`] unless `len` bytes at `addr` fit
+/// in the 32-bit address space.
+fn check_region(addr: u32, len: u64) -> Result<()> {
+    if addr as u64 + len <= 1 << 32 {
+        return Ok(());
+    }
+    let msg = format!("{len:#x} bytes at {addr:#010x} exceed the 32-bit address space");
+    Err(io::Error::new(ErrorKind::InvalidInput, msg))
+}
+
+/// What nusb said about a failed transfer, if it was nusb
+fn transfer_status(e: &io::Error) -> Option<&TransferError> {
+    e.get_ref().and_then(|e| e.downcast_ref::<TransferError>())
+}
+
+/// How long the two halves of [`Kendryte::self_test`] took
+#[derive(Clone, Copy, Debug)]
+pub struct SelfTest {
+    pub write: Duration,
+    pub read: Duration,
+}
+
+/// Bytes that do not repeat every 256, so that data landing at the wrong
+/// offset does not compare equal by chance
+fn test_pattern(len: usize) -> Vec<u8> {
+    let mut x: u32 = 0x2545_f491;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect()
+}
+
+/// Fail with [`ErrorKind::InvalidData`] and a [`Mismatch`] inside at the
+/// first byte where `actual` differs, read from `addr + offset` on the device.
+fn compare(addr: u32, offset: u64, expected: &[u8], actual: &[u8]) -> Result<()> {
+    let Some(p) = expected.iter().zip(actual).position(|(e, a)| e != a) else {
+        return Ok(());
+    };
+    let offset = offset + p as u64;
+    let mismatch = Mismatch {
+        offset,
+        addr: addr.wrapping_add(offset as u32),
+        expected: expected[p],
+        actual: actual[p],
+    };
+    Err(io::Error::new(ErrorKind::InvalidData, mismatch))
+}
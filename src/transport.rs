@@ -0,0 +1,382 @@
+//! The USB transfers the protocol is built on, so that it can run against
+//! something else than a real device
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::{self, ErrorKind, Result};
+use std::thread;
+use std::time::Duration;
+
+use async_io::{block_on, Timer};
+use futures_lite::FutureExt;
+use log::info;
+use nusb::{
+    transfer::{ControlIn, ControlOut, ControlType, Queue, Recipient, RequestBuffer},
+    Interface,
+};
+
+use crate::{EP0_GET_CPU_INFO, EP0_SET_DATA_ADDRESS};
+
+/// Transfers to a device in mask ROM USB loader mode
+pub trait Transport {
+    /// Issue a vendor control-in request, returning the number of bytes read.
+    fn control_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize>;
+
+    /// Issue a vendor control-out request without data.
+    fn control_out(&self, request: u8, value: u16, index: u16, timeout: Duration) -> Result<()>;
+
+    fn bulk_out(&self, ep: u8, data: &[u8], timeout: Duration) -> Result<()>;
+
+    /// Read up to `buf.len()` bytes, returning the number of bytes read.
+    fn bulk_in(&self, ep: u8, buf: &mut [u8], timeout: Duration) -> Result<usize>;
+
+    fn clear_halt(&self, ep: u8) -> Result<()>;
+
+    /// A queue to keep several bulk OUT transfers in flight, if supported
+    fn bulk_out_queue(&self, _ep: u8) -> Option<Queue<Vec<u8>>> {
+        None
+    }
+}
+
+thread_local! {
+    /// Buffer of the last bulk OUT transfer, to reuse for the next one
+    static OUT_BUF: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+/// Run `fut` to completion, or fail with [`ErrorKind::TimedOut`].
+pub(crate) fn block_on_timeout<T>(
+    timeout: Duration,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    block_on(fut.or(async {
+        Timer::after(timeout).await;
+        Err(ErrorKind::TimedOut.into())
+    }))
+}
+
+impl Transport for Interface {
+    fn control_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize> {
+        block_on_timeout(timeout, async {
+            let ci = ControlIn {
+                control_type: ControlType::Vendor,
+                recipient: Recipient::Device,
+                request,
+                value,
+                index,
+                length: buf.len() as u16,
+            };
+            let comp = Interface::control_in(self, ci).await;
+            comp.status.map_err(io::Error::other)?;
+
+            // The device may send less than requested, but never more.
+            let n = comp.data.len().min(buf.len());
+            buf[..n].copy_from_slice(&comp.data[..n]);
+            Ok(n)
+        })
+    }
+
+    fn control_out(&self, request: u8, value: u16, index: u16, timeout: Duration) -> Result<()> {
+        block_on_timeout(timeout, async {
+            let co = ControlOut {
+                control_type: ControlType::Vendor,
+                recipient: Recipient::Device,
+                request,
+                value,
+                index,
+                data: &[],
+            };
+            let comp = Interface::control_out(self, co).await;
+            comp.status.map_err(io::Error::other)?;
+            Ok(())
+        })
+    }
+
+    fn bulk_out(&self, ep: u8, data: &[u8], timeout: Duration) -> Result<()> {
+        // nusb takes the data by value; copy it to the buffer of the last
+        // transfer instead of allocating one per chunk.
+        let mut buf = OUT_BUF.take();
+        buf.clear();
+        buf.extend_from_slice(data);
+        block_on_timeout(timeout, async {
+            let comp = Interface::bulk_out(self, ep, buf).await;
+            OUT_BUF.set(comp.data.reuse());
+            comp.status?;
+            Ok(())
+        })
+    }
+
+    fn bulk_in(&self, ep: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        block_on_timeout(timeout, async {
+            let req = RequestBuffer::new(buf.len());
+            let comp = Interface::bulk_in(self, ep, req).await;
+            comp.status.map_err(io::Error::other)?;
+
+            let n = comp.data.len().min(buf.len());
+            buf[..n].copy_from_slice(&comp.data[..n]);
+            Ok(n)
+        })
+    }
+
+    fn clear_halt(&self, ep: u8) -> Result<()> {
+        Interface::clear_halt(self, ep)
+    }
+
+    fn bulk_out_queue(&self, ep: u8) -> Option<Queue<Vec<u8>>> {
+        Some(Interface::bulk_out_queue(self, ep))
+    }
+}
+
+/// Logs every transfer instead of sending it, and succeeds. Bulk transfers
+/// are shown with the address they would go to; reads return zeros.
+#[derive(Debug, Default)]
+pub struct DryRun {
+    addr: Cell<u32>,
+}
+
+impl DryRun {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn take_addr(&self, n: usize) -> u32 {
+        let addr = self.addr.get();
+        self.addr.set(addr.wrapping_add(n as u32));
+        addr
+    }
+}
+
+impl Transport for DryRun {
+    fn control_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> Result<usize> {
+        let length = buf.len();
+        info!("dry run: control in: request {request:#04x} value {value:#06x} index {index:#06x} length {length}");
+        buf.fill(0);
+        Ok(length)
+    }
+
+    fn control_out(&self, request: u8, value: u16, index: u16, _timeout: Duration) -> Result<()> {
+        info!("dry run: control out: request {request:#04x} value {value:#06x} index {index:#06x}");
+        if request == EP0_SET_DATA_ADDRESS {
+            self.addr.set((value as u32) << 16 | index as u32);
+        }
+        Ok(())
+    }
+
+    fn bulk_out(&self, ep: u8, data: &[u8], _timeout: Duration) -> Result<()> {
+        let addr = self.take_addr(data.len());
+        info!(
+            "dry run: bulk out: endpoint {ep:#04x} length {} to {addr:#010x}",
+            data.len()
+        );
+        Ok(())
+    }
+
+    fn bulk_in(&self, ep: u8, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+        let addr = self.take_addr(buf.len());
+        info!(
+            "dry run: bulk in: endpoint {ep:#04x} length {} from {addr:#010x}",
+            buf.len()
+        );
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn clear_halt(&self, ep: u8) -> Result<()> {
+        info!("dry run: clear halt: endpoint {ep:#04x}");
+        Ok(())
+    }
+}
+
+/// A transfer seen by [`MockTransport`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    ControlIn {
+        request: u8,
+        value: u16,
+        index: u16,
+        length: usize,
+    },
+    ControlOut {
+        request: u8,
+        value: u16,
+        index: u16,
+    },
+    BulkOut {
+        ep: u8,
+        data: Vec<u8>,
+    },
+    BulkIn {
+        ep: u8,
+        length: usize,
+    },
+    ClearHalt {
+        ep: u8,
+    },
+}
+
+/// Records all transfers for tests, and acts like device memory: bulk
+/// transfers write and read at the address last set, moving it along.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    calls: RefCell<Vec<Call>>,
+    addr: Cell<u32>,
+    memory: RefCell<BTreeMap<u32, u8>>,
+    cpu_info: Vec<u8>,
+    bad_address: Option<u32>,
+    latency: Duration,
+    discard: bool,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the reply to [`EP0_GET_CPU_INFO`].
+    pub fn with_cpu_info(mut self, info: &[u8]) -> Self {
+        self.cpu_info = info.to_vec();
+        self
+    }
+
+    /// Fail every bulk OUT transfer that would write to `addr`.
+    pub fn with_bad_address(mut self, addr: u32) -> Self {
+        self.bad_address = Some(addr);
+        self
+    }
+
+    /// Take `latency` for every bulk transfer, like a device would, e.g. to
+    /// benchmark the host side with realistic numbers.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Neither record bulk OUT transfers nor keep their data, for sending
+    /// more than is worth keeping around.
+    pub fn discarding(mut self) -> Self {
+        self.discard = true;
+        self
+    }
+
+    /// All transfers so far, oldest first
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.borrow().clone()
+    }
+
+    /// Memory contents at `addr`, where never written reads as zero
+    pub fn memory(&self, addr: u32, len: usize) -> Vec<u8> {
+        let memory = self.memory.borrow();
+        (0..len as u32)
+            .map(|o| memory.get(&addr.wrapping_add(o)).copied().unwrap_or(0))
+            .collect()
+    }
+
+    fn record(&self, call: Call) {
+        self.calls.borrow_mut().push(call);
+    }
+
+    /// Advance the address by `n`, returning where to start.
+    fn take_addr(&self, n: usize) -> u32 {
+        let addr = self.addr.get();
+        self.addr.set(addr.wrapping_add(n as u32));
+        addr
+    }
+}
+
+impl Transport for MockTransport {
+    fn control_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> Result<usize> {
+        let length = buf.len();
+        self.record(Call::ControlIn {
+            request,
+            value,
+            index,
+            length,
+        });
+        let reply: &[u8] = match request {
+            EP0_GET_CPU_INFO => &self.cpu_info,
+            _ => &[],
+        };
+        let n = reply.len().min(length);
+        buf[..n].copy_from_slice(&reply[..n]);
+        Ok(n)
+    }
+
+    fn control_out(&self, request: u8, value: u16, index: u16, _timeout: Duration) -> Result<()> {
+        self.record(Call::ControlOut {
+            request,
+            value,
+            index,
+        });
+        if request == EP0_SET_DATA_ADDRESS {
+            self.addr.set((value as u32) << 16 | index as u32);
+        }
+        Ok(())
+    }
+
+    fn bulk_out(&self, ep: u8, data: &[u8], _timeout: Duration) -> Result<()> {
+        thread::sleep(self.latency);
+        if self.discard {
+            self.take_addr(data.len());
+            return Ok(());
+        }
+        self.record(Call::BulkOut {
+            ep,
+            data: data.to_vec(),
+        });
+        if let Some(bad) = self.bad_address {
+            if bad.wrapping_sub(self.addr.get()) < data.len() as u32 {
+                return Err(io::Error::other(format!("bad address {bad:#010x}")));
+            }
+        }
+        let addr = self.take_addr(data.len());
+        let mut memory = self.memory.borrow_mut();
+        for (o, b) in data.iter().enumerate() {
+            memory.insert(addr.wrapping_add(o as u32), *b);
+        }
+        Ok(())
+    }
+
+    fn bulk_in(&self, ep: u8, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+        thread::sleep(self.latency);
+        self.record(Call::BulkIn {
+            ep,
+            length: buf.len(),
+        });
+        let addr = self.take_addr(buf.len());
+        buf.copy_from_slice(&self.memory(addr, buf.len()));
+        Ok(buf.len())
+    }
+
+    fn clear_halt(&self, ep: u8) -> Result<()> {
+        self.record(Call::ClearHalt { ep });
+        Ok(())
+    }
+}
@@ -0,0 +1,572 @@
+//! Finding devices, claiming the interface and discovering endpoints
+
+use std::io::{self, ErrorKind, Result};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use async_io::{block_on, Timer};
+use log::{debug, warn};
+use nusb::{
+    descriptors::{Configuration, InterfaceAltSetting},
+    transfer::{Direction, EndpointType},
+    Device, DeviceInfo, Interface, Speed,
+};
+
+use crate::{Chip, Error, Kendryte};
+
+pub const CLAIM_INTERFACE_TIMEOUT: Duration = Duration::from_secs(1);
+pub const CLAIM_INTERFACE_PERIOD: Duration = Duration::from_micros(200);
+/// How long each attempt of [`Kendryte::open_with`] tries to claim the
+/// interface before opening the device again
+const OPEN_ATTEMPT_CLAIM_TIMEOUT: Duration = Duration::from_millis(250);
+const OPEN_RETRY_PERIOD: Duration = Duration::from_millis(50);
+
+/// Keep trying to claim interface `ii` for `timeout`, returning the last
+/// error if it never works out.
+pub fn claim_interface(d: &Device, ii: u8, timeout: Duration) -> Result<Interface> {
+    claim_interface_with(d, ii, timeout, CLAIM_INTERFACE_PERIOD)
+}
+
+/// Like [`claim_interface`], waiting `period` between attempts.
+pub fn claim_interface_with(
+    d: &Device,
+    ii: u8,
+    timeout: Duration,
+    period: Duration,
+) -> Result<Interface> {
+    let end = Instant::now() + timeout;
+    // Even with no time to wait, try once.
+    block_on(async {
+        loop {
+            let e = match d.claim_interface(ii) {
+                Ok(i) => return Ok(i),
+                Err(e) => e,
+            };
+            if Instant::now() >= end {
+                return Err(e);
+            }
+            Timer::after(period).await;
+        }
+    })
+}
+
+/// How a device is connected, as far as transfers care
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub speed: Speed,
+    /// Of bulk endpoints at that speed
+    pub max_packet_size: usize,
+}
+
+impl LinkInfo {
+    /// The link `di` is connected with. A speed that is not known, or not
+    /// reported, is taken as full speed, which is slower but works.
+    pub fn of(di: &DeviceInfo) -> Self {
+        let (speed, max_packet_size) = match di.speed() {
+            Some(s @ (Speed::Full | Speed::Low)) => (s, 64),
+            Some(Speed::High) => (Speed::High, 512),
+            Some(s @ (Speed::Super | Speed::SuperPlus)) => (s, 1024),
+            other => {
+                warn!("Unknown USB device speed {other:?}, assuming full speed");
+                (Speed::Full, 64)
+            }
+        };
+        Self {
+            speed,
+            max_packet_size,
+        }
+    }
+}
+
+/// List all connected devices matching `vid` and `pid`.
+pub fn find_devices(vid: u16, pid: u16) -> Result<Vec<DeviceInfo>> {
+    let devices = nusb::list_devices()?
+        .filter(|d| d.vendor_id() == vid && d.product_id() == pid)
+        .collect();
+    Ok(devices)
+}
+
+/// List all connected devices matching any of `chips`.
+pub fn find_chip_devices(chips: &[Chip]) -> Result<Vec<DeviceInfo>> {
+    let devices = nusb::list_devices()?
+        .filter(|d| {
+            Chip::from_ids(d.vendor_id(), d.product_id()).is_some_and(|c| chips.contains(&c))
+        })
+        .collect();
+    Ok(devices)
+}
+
+/// Bus number and hub ports leading to the device, e.g. `3-1.2`, which stays
+/// the same for a device that is plugged in the same place. Not known on
+/// Windows.
+pub fn port_path(di: &DeviceInfo) -> Option<String> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        // The name of the device in sysfs is just that.
+        di.sysfs_path()
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(String::from)
+    }
+    #[cfg(target_os = "macos")]
+    {
+        // The bus in the top byte, then a port per nibble until zero
+        let id = di.location_id();
+        let ports: Vec<_> = (0..6)
+            .rev()
+            .map(|i| (id >> (i * 4)) & 0xf)
+            .take_while(|&p| p != 0)
+            .map(|p| p.to_string())
+            .collect();
+        Some(format!("{}-{}", id >> 24, ports.join(".")))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    {
+        let _ = di;
+        None
+    }
+}
+
+pub const WAIT_DEVICE_PERIOD: Duration = Duration::from_millis(100);
+pub const MAX_WAIT_DEVICE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How to poll the bus while waiting for a device: first after `period`,
+/// doubling each time up to `max_period`, and for at most `retries` polls
+/// after the first if given
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub period: Duration,
+    pub max_period: Duration,
+    pub retries: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            period: WAIT_DEVICE_PERIOD,
+            max_period: MAX_WAIT_DEVICE_PERIOD,
+            retries: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// How long to sleep before retry number `retry`, counted from 0, or
+    /// None if there are no retries left
+    pub fn delay(&self, retry: u32) -> Option<Duration> {
+        if self.retries.is_some_and(|n| retry >= n) {
+            return None;
+        }
+        let factor = 1_u32.checked_shl(retry).unwrap_or(u32::MAX);
+        Some(self.period.saturating_mul(factor).min(self.max_period))
+    }
+
+    /// Poll `done` until it returns true, or fail with `what` once `timeout`
+    /// has passed or the retries are used up.
+    fn poll(
+        &self,
+        timeout: Duration,
+        what: &str,
+        mut done: impl FnMut() -> Result<bool>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let mut retry = 0;
+        loop {
+            if done()? {
+                return Ok(());
+            }
+            let delay = match self.delay(retry) {
+                Some(_) if start.elapsed() >= timeout => None,
+                delay => delay,
+            };
+            let Some(delay) = delay else {
+                return Err(io::Error::new(ErrorKind::TimedOut, what));
+            };
+            retry += 1;
+            debug!("{what}, retry {retry} in {delay:?}");
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Poll the bus until a device satisfying `matches` shows up.
+pub fn wait_for_device(
+    timeout: Duration,
+    matches: impl FnMut(&DeviceInfo) -> bool,
+) -> Result<DeviceInfo> {
+    wait_for_device_with(timeout, &Backoff::default(), matches)
+}
+
+/// Like [`wait_for_device`], polling as `backoff` says.
+pub fn wait_for_device_with(
+    timeout: Duration,
+    backoff: &Backoff,
+    mut matches: impl FnMut(&DeviceInfo) -> bool,
+) -> Result<DeviceInfo> {
+    let mut found = None;
+    backoff.poll(timeout, "device did not show up in time", || {
+        found = nusb::list_devices()?.find(&mut matches);
+        Ok(found.is_some())
+    })?;
+    Ok(found.expect("polled until found"))
+}
+
+/// Poll the bus until no device satisfies `matches` anymore.
+pub fn wait_for_device_gone(
+    timeout: Duration,
+    matches: impl FnMut(&DeviceInfo) -> bool,
+) -> Result<()> {
+    wait_for_device_gone_with(timeout, &Backoff::default(), matches)
+}
+
+/// Like [`wait_for_device_gone`], polling as `backoff` says.
+pub fn wait_for_device_gone_with(
+    timeout: Duration,
+    backoff: &Backoff,
+    mut matches: impl FnMut(&DeviceInfo) -> bool,
+) -> Result<()> {
+    backoff.poll(timeout, "device is still there", || {
+        Ok(!nusb::list_devices()?.any(|d| matches(&d)))
+    })
+}
+
+/// List endpoints with address, direction and transfer type.
+pub fn describe_endpoints(s: &InterfaceAltSetting) -> String {
+    let eps: Vec<_> = s
+        .endpoints()
+        .map(|e| {
+            let (addr, dir, ty) = (e.address(), e.direction(), e.transfer_type());
+            format!("{addr:#04x} {dir:?} {ty:?}")
+        })
+        .collect();
+    match eps.is_empty() {
+        true => "no endpoints".into(),
+        false => eps.join(", "),
+    }
+}
+
+/// Find the first OUT and IN endpoint addresses of the first interface,
+/// bulk ones if there are any.
+pub fn find_endpoints(d: &Device) -> std::result::Result<(u8, u8), Error> {
+    let c = d
+        .configurations()
+        .next()
+        .ok_or(Error::Descriptor("configurations"))?;
+    select_endpoints(&find_alt_setting(&c, None, None)?, None, None)
+}
+
+/// Alternate setting `alt` of `interface`, by default the first interface
+/// and its first alternate setting.
+pub fn find_alt_setting<'a>(
+    c: &Configuration<'a>,
+    interface: Option<u8>,
+    alt: Option<u8>,
+) -> std::result::Result<InterfaceAltSetting<'a>, Error> {
+    let mut settings = c.interface_alt_settings();
+    let Some(interface) = interface else {
+        return settings
+            .find(|s| alt.is_none_or(|a| s.alternate_setting() == a))
+            .ok_or(Error::Descriptor("interface settings"));
+    };
+    let alt = alt.unwrap_or(0);
+    settings
+        .find(|s| s.interface_number() == interface && s.alternate_setting() == alt)
+        .ok_or(Error::NoInterface { interface, alt })
+}
+
+/// Log all interfaces, alternate settings and endpoints of `d`.
+fn log_topology(d: &Device) {
+    for c in d.configurations() {
+        for s in c.interface_alt_settings() {
+            debug!(
+                "configuration {} interface {} alternate setting {} class {:#04x}: {}",
+                c.configuration_value(),
+                s.interface_number(),
+                s.alternate_setting(),
+                s.class(),
+                describe_endpoints(&s)
+            );
+        }
+    }
+}
+
+/// Like [`find_endpoints`] for the alternate setting `s`, but use `out_ep`
+/// and `in_ep` where given, after checking that they exist and point the
+/// right way.
+pub fn select_endpoints(
+    s: &InterfaceAltSetting,
+    out_ep: Option<u8>,
+    in_ep: Option<u8>,
+) -> std::result::Result<(u8, u8), Error> {
+    let pick = |endpoint: Option<u8>, dir: Direction, direction| match endpoint {
+        None => {
+            let es: Vec<_> = s.endpoints().filter(|e| e.direction() == dir).collect();
+            let bulk = es.iter().find(|e| e.transfer_type() == EndpointType::Bulk);
+            match (bulk, es.first()) {
+                (Some(e), _) => Ok(e.address()),
+                (None, Some(e)) => {
+                    warn!(
+                        "No bulk {direction} endpoint, using {:#04x} ({:?})",
+                        e.address(),
+                        e.transfer_type()
+                    );
+                    Ok(e.address())
+                }
+                (None, None) => Err(Error::NoEndpoint {
+                    direction,
+                    found: describe_endpoints(s),
+                }),
+            }
+        }
+        Some(endpoint) => match s.endpoints().find(|e| e.address() == endpoint) {
+            Some(e) if e.direction() == dir => Ok(endpoint),
+            Some(_) => Err(Error::EndpointDirection {
+                endpoint,
+                direction,
+            }),
+            None => Err(Error::MissingEndpoint {
+                endpoint,
+                found: describe_endpoints(s),
+            }),
+        },
+    };
+    Ok((
+        pick(out_ep, Direction::Out, "OUT")?,
+        pick(in_ep, Direction::In, "IN")?,
+    ))
+}
+
+/// The USB version from the device descriptor (`bcdUSB`), e.g. 0x0200
+pub fn usb_version(d: &Device) -> Result<u16> {
+    const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+    let desc = d.get_descriptor(DESCRIPTOR_TYPE_DEVICE, 0, 0, CLAIM_INTERFACE_TIMEOUT)?;
+    match desc.get(2..4) {
+        Some(v) => Ok(u16::from_le_bytes([v[0], v[1]])),
+        None => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "short device descriptor",
+        )),
+    }
+}
+
+/// Warn when a device capable of USB 2.0 or later only runs at full speed,
+/// which is a cable or port problem.
+fn check_speed(di: &DeviceInfo, d: &Device) {
+    if !matches!(di.speed(), Some(Speed::Full | Speed::Low)) {
+        return;
+    }
+    match usb_version(d) {
+        Ok(v) if v >= 0x0200 => warn!(
+            "USB {}.{:02x} device is running at {:?} speed only, check the cable and port",
+            v >> 8,
+            v & 0xff,
+            di.speed().unwrap()
+        ),
+        Ok(_) => {}
+        Err(e) => debug!("cannot read device descriptor: {e}"),
+    }
+}
+
+/// Explain why claiming interface `ii` of `di` failed, as far as it can be
+/// told from the error.
+#[cfg_attr(not(windows), allow(unused_variables))]
+fn claim_error(di: &DeviceInfo, ii: u8, source: io::Error) -> Error {
+    match source.kind() {
+        ErrorKind::ResourceBusy => Error::Busy {
+            interface: ii,
+            source,
+        },
+        // nusb can only open interfaces bound to WinUSB there.
+        #[cfg(windows)]
+        ErrorKind::Unsupported => Error::WinUsb {
+            interface: ii,
+            driver: di.driver().unwrap_or("unknown").into(),
+            source,
+        },
+        _ => Error::Claim {
+            interface: ii,
+            source,
+        },
+    }
+}
+
+/// `di` as listed now, found by where it is plugged in
+fn relist(di: &DeviceInfo) -> Option<DeviceInfo> {
+    let port = port_path(di);
+    nusb::list_devices().ok()?.find(|d| {
+        let same_place = match &port {
+            Some(p) => port_path(d).as_ref() == Some(p),
+            None => d.device_address() == di.device_address(),
+        };
+        same_place
+            && d.bus_number() == di.bus_number()
+            && d.vendor_id() == di.vendor_id()
+            && d.product_id() == di.product_id()
+    })
+}
+
+/// How to open a device, see [`Kendryte::open_with`]
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    claim_timeout: Duration,
+    claim_period: Duration,
+    out_ep: Option<u8>,
+    in_ep: Option<u8>,
+    interface: Option<u8>,
+    alt_setting: Option<u8>,
+    detach_kernel_driver: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            claim_timeout: CLAIM_INTERFACE_TIMEOUT,
+            claim_period: CLAIM_INTERFACE_PERIOD,
+            out_ep: None,
+            in_ep: None,
+            interface: None,
+            alt_setting: None,
+            detach_kernel_driver: false,
+        }
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep retrying to claim the interface for `timeout`.
+    pub fn with_claim_timeout(mut self, timeout: Duration) -> Self {
+        self.claim_timeout = timeout;
+        self
+    }
+
+    /// Wait `period` between attempts to claim the interface.
+    pub fn with_claim_period(mut self, period: Duration) -> Self {
+        self.claim_period = period;
+        self
+    }
+
+    /// Use this bulk OUT endpoint instead of the first one found.
+    pub fn with_out_ep(mut self, ep: Option<u8>) -> Self {
+        self.out_ep = ep;
+        self
+    }
+
+    /// Use this bulk IN endpoint instead of the first one found.
+    pub fn with_in_ep(mut self, ep: Option<u8>) -> Self {
+        self.in_ep = ep;
+        self
+    }
+
+    /// Claim this interface instead of the first one.
+    pub fn with_interface(mut self, interface: Option<u8>) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    /// Select this alternate setting of the interface.
+    pub fn with_alt_setting(mut self, alt: Option<u8>) -> Self {
+        self.alt_setting = alt;
+        self
+    }
+
+    /// Detach a kernel driver bound to the interface before claiming it.
+    /// This only does something on Linux.
+    pub fn with_detach_kernel_driver(mut self, detach: bool) -> Self {
+        self.detach_kernel_driver = detach;
+        self
+    }
+}
+
+impl Kendryte<Interface> {
+    /// Open the device and claim its first interface.
+    pub fn open(di: &DeviceInfo) -> std::result::Result<Self, Error> {
+        Self::open_with(di, &OpenOptions::new())
+    }
+
+    /// Like [`Kendryte::open`], retrying to claim the interface for `timeout`.
+    pub fn open_with_claim_timeout(
+        di: &DeviceInfo,
+        timeout: Duration,
+    ) -> std::result::Result<Self, Error> {
+        Self::open_with(di, &OpenOptions::new().with_claim_timeout(timeout))
+    }
+
+    /// Like [`Kendryte::open`], as set up by `opts`.
+    /// When opening or claiming fails, e.g. as the OS is still setting up
+    /// a device that just showed up, the device is looked up again and
+    /// reopened until the claim timeout.
+    pub fn open_with(di: &DeviceInfo, opts: &OpenOptions) -> std::result::Result<Self, Error> {
+        let deadline = Instant::now() + opts.claim_timeout;
+        let mut di = di.clone();
+        let mut attempt = 1;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let e = match Self::open_once(&di, opts, left.min(OPEN_ATTEMPT_CLAIM_TIMEOUT)) {
+                Err(e @ (Error::Open(_) | Error::Claim { .. } | Error::Busy { .. })) => e,
+                r => return r,
+            };
+            if Instant::now() + OPEN_RETRY_PERIOD >= deadline {
+                return Err(e);
+            }
+            debug!("opening device failed (attempt {attempt}), retrying: {e}");
+            thread::sleep(OPEN_RETRY_PERIOD);
+            match relist(&di) {
+                Some(d) => di = d,
+                None => debug!("device is not listed anymore"),
+            }
+            attempt += 1;
+        }
+    }
+
+    fn open_once(
+        di: &DeviceInfo,
+        opts: &OpenOptions,
+        timeout: Duration,
+    ) -> std::result::Result<Self, Error> {
+        let d = di.open().map_err(Error::Open)?;
+        check_speed(di, &d);
+        log_topology(&d);
+
+        let c = d
+            .configurations()
+            .next()
+            .ok_or(Error::Descriptor("configurations"))?;
+        let s = find_alt_setting(&c, opts.interface, opts.alt_setting)?;
+        let (ii, alt) = (s.interface_number(), s.alternate_setting());
+        debug!("using interface {ii} alternate setting {alt}");
+        if opts.detach_kernel_driver {
+            match d.detach_kernel_driver(ii) {
+                Ok(()) => debug!("detached kernel driver from interface {ii}"),
+                Err(e) => debug!("detaching kernel driver from interface {ii} failed: {e}"),
+            }
+        }
+        let i = claim_interface_with(&d, ii, timeout, opts.claim_period)
+            .map_err(|e| claim_error(di, ii, e))?;
+        debug!("claimed interface {ii}");
+        if alt != 0 {
+            i.set_alt_setting(alt).map_err(|source| Error::AltSetting {
+                interface: ii,
+                alt,
+                source,
+            })?;
+        }
+
+        let (e_out_addr, e_in_addr) = select_endpoints(&s, opts.out_ep, opts.in_ep)?;
+        debug!("using OUT endpoint {e_out_addr:#04x}, IN endpoint {e_in_addr:#04x}");
+
+        // A previous run may have left an endpoint halted.
+        for ep in [e_out_addr, e_in_addr] {
+            if let Err(e) = i.clear_halt(ep) {
+                debug!("clearing halt on endpoint {ep:#04x} failed: {e}");
+            }
+        }
+        Ok(Self::new(i, e_out_addr, e_in_addr).with_link_info(LinkInfo::of(di)))
+    }
+
+    pub fn interface(&self) -> &Interface {
+        self.transport()
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+fn kendryte_boot(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_kendryte_boot"))
+        .args(args)
+        .output()
+        .expect("binary runs")
+}
+
+#[test]
+fn arg_file_expands_to_its_lines() {
+    let dir = std::env::temp_dir().join(format!("kendryte_boot-args-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let data = dir.join("data with spaces.bin");
+    fs::write(&data, b"123456789").unwrap();
+    let data = data.to_str().unwrap();
+    let args = dir.join("args");
+    fs::write(
+        &args,
+        format!("# checksum a file\nchecksum\n\n  {data}  \n"),
+    )
+    .unwrap();
+
+    let direct = kendryte_boot(&["checksum", data]);
+    let from_file = kendryte_boot(&[&format!("@{}", args.display())]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(direct.status.success());
+    assert_eq!(from_file.status.code(), direct.status.code());
+    assert_eq!(from_file.stdout, direct.stdout);
+    // The CRC32 check value
+    assert!(String::from_utf8_lossy(&direct.stdout).starts_with("cbf43926"));
+}
+
+#[test]
+fn checksum_algos_give_their_check_values() {
+    let file = std::env::temp_dir().join(format!("kendryte_boot-sum-{}", std::process::id()));
+    fs::write(&file, b"123456789").unwrap();
+    let file = file.to_str().unwrap();
+    let sum = |algo| {
+        let out = kendryte_boot(&["checksum", "--algo", algo, file]);
+        assert!(out.status.success());
+        let out = String::from_utf8(out.stdout).unwrap();
+        out.split_whitespace().next().unwrap().to_string()
+    };
+
+    assert_eq!(sum("crc16-ccitt"), "29b1");
+    #[cfg(feature = "sha256")]
+    assert_eq!(
+        sum("sha256"),
+        "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+    );
+    fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn missing_arg_file_is_a_usage_error() {
+    let out = kendryte_boot(&["@/nonexistent/kendryte_boot-args"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("/nonexistent/kendryte_boot-args"));
+}
+
+#[test]
+fn unknown_address_name_lists_the_presets() {
+    let out = kendryte_boot(&["--dry-run", "load", "--address", "ocram+0x10", "x.bin"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("sram, dram, rom"));
+}
+
+#[test]
+fn dump_past_the_address_space_is_refused() {
+    let out = kendryte_boot(&["--dry-run", "dump", "-a", "0xffffff00", "0x200"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("exceeds the 32-bit address space"));
+
+    let out = kendryte_boot(&["--dry-run", "dump", "-a", "0xffffff00", "0x100"]);
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn dump_output_that_cannot_be_written_is_a_file_error() {
+    let out = kendryte_boot(&[
+        "--dry-run",
+        "dump",
+        "-a",
+        "0x80000000",
+        "0x100",
+        "/dev/full",
+    ]);
+    assert_eq!(out.status.code(), Some(5));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("/dev/full: No space left"));
+}
+
+#[test]
+fn json_result_is_all_there_is_on_stdout() {
+    let args = ["--dry-run", "--output-format", "json", "peek", "0x80360000"];
+    let out = kendryte_boot(&args);
+    assert_eq!(out.status.code(), Some(0));
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let result: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    // The CPU info and the word peeked at
+    assert_eq!(result["bytes_in"], 0x20 + 4);
+    assert_eq!(result["bytes_out"], 0);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("0x80360000: 0x00000000"));
+}
+
+#[test]
+fn json_errors_are_silenced_by_qq() {
+    let args = [
+        "--dry-run",
+        "--output-format",
+        "json",
+        "dump",
+        "-a",
+        "0xffffff00",
+        "0x200",
+    ];
+    let out = kendryte_boot(&[&["-q"], &args[..]].concat());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("\"kind\":\"invalid_region\""));
+    let out = kendryte_boot(&[&["-qq"], &args[..]].concat());
+    assert_eq!(out.status.code(), Some(1));
+    assert!(
+        out.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+}
+
+#[test]
+fn bad_input_and_mismatches_have_their_own_exit_codes() {
+    let dir = std::env::temp_dir().join(format!("kendryte_boot-exit-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // Only the checksum at the end is off, so this fails while loading.
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(&[0x5a; 4096]).unwrap();
+    let mut gz = gz.finish().unwrap();
+    let crc = gz.len() - 8;
+    gz[crc] ^= 0xff;
+    let bad = dir.join("bad.bin.gz");
+    fs::write(&bad, gz).unwrap();
+    let out = kendryte_boot(&["--dry-run", "load", bad.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(5));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot read data"));
+
+    // A dry run reads back zeros.
+    let data = dir.join("fw.bin");
+    fs::write(&data, b"0123").unwrap();
+    let out = kendryte_boot(&["--dry-run", "load", "--verify", data.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(6));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("mismatch at offset 0x0"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "sha256")]
+#[test]
+fn manifest_records_sha256_of_loaded_data() {
+    let dir = std::env::temp_dir().join(format!("kendryte_boot-manifest-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let data = dir.join("fw.bin");
+    fs::write(&data, b"0123456789").unwrap();
+    let manifest = dir.join("manifest.json");
+
+    let out = kendryte_boot(&[
+        "--dry-run",
+        "load",
+        "--skip",
+        "1",
+        "--manifest",
+        manifest.to_str().unwrap(),
+        data.to_str().unwrap(),
+    ]);
+    let json = fs::read_to_string(&manifest).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(out.status.success());
+    // SHA-256 of "123456789", what was sent after --skip
+    assert!(json.contains("15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"));
+    assert!(json.contains("\"bytes\": 9"));
+}
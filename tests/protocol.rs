@@ -0,0 +1,275 @@
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use kendryte_boot::{
+    timeout_for, Call, Cancelled, Chip, ChunkError, Error, InputError, Kendryte, Mismatch,
+    MockTransport, TransferCounts, EP0_SET_DATA_ADDRESS, EP0_SET_DATA_LENGTH, SRAM_RUN_BASE,
+    TRANSFER_TIMEOUT,
+};
+use nusb::Speed;
+
+const OUT_EP: u8 = 0x01;
+const IN_EP: u8 = 0x81;
+
+fn kendryte() -> Kendryte<MockTransport> {
+    Kendryte::new(MockTransport::new(), OUT_EP, IN_EP)
+}
+
+fn set_addr(addr: u32) -> Call {
+    Call::ControlOut {
+        request: EP0_SET_DATA_ADDRESS,
+        value: (addr >> 16) as u16,
+        index: addr as u16,
+    }
+}
+
+fn bulk_out_lengths(calls: &[Call]) -> Vec<usize> {
+    calls
+        .iter()
+        .filter_map(|c| match c {
+            Call::BulkOut { ep: OUT_EP, data } => Some(data.len()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn load_splits_into_chunks() {
+    let k = kendryte();
+    let data = vec![0x5a; 1025];
+    let sent = k.load(SRAM_RUN_BASE, &data[..]).unwrap();
+    assert_eq!(sent, 1025);
+
+    let calls = k.transport().calls();
+    assert_eq!(calls[0], set_addr(SRAM_RUN_BASE));
+    assert_eq!(calls.len(), 4);
+    assert_eq!(bulk_out_lengths(&calls), [512, 512, 1]);
+    assert_eq!(k.transport().memory(SRAM_RUN_BASE, 1025), data);
+}
+
+#[test]
+fn load_announces_length() {
+    let k = kendryte();
+    k.load_with_progress(SRAM_RUN_BASE, &[1, 2, 3][..], Some(3), |_| {})
+        .unwrap();
+
+    let calls = k.transport().calls();
+    assert_eq!(calls[0], set_addr(SRAM_RUN_BASE));
+    let len = Call::ControlOut {
+        request: EP0_SET_DATA_LENGTH,
+        value: 0,
+        index: 3,
+    };
+    assert_eq!(calls[1], len);
+    assert_eq!(bulk_out_lengths(&calls), [3]);
+}
+
+#[test]
+fn verify_reads_back() {
+    let k = kendryte();
+    let data: Vec<u8> = (0..=255).collect();
+    k.load(SRAM_RUN_BASE, &data[..]).unwrap();
+    k.verify(SRAM_RUN_BASE, &data[..]).unwrap();
+
+    let mut other = data.clone();
+    other[100] ^= 0xff;
+    let e = k.verify(SRAM_RUN_BASE, &other[..]).unwrap_err();
+    assert!(e.to_string().contains("offset 0x64"), "{e}");
+}
+
+#[test]
+fn transferred_bytes_are_counted() {
+    let counts = Arc::new(TransferCounts::default());
+    let k = kendryte().with_counts(counts.clone());
+    k.load(SRAM_RUN_BASE, &[0; 1000][..]).unwrap();
+    k.read(SRAM_RUN_BASE, &mut [0; 16]).unwrap();
+    let mock = MockTransport::new().with_cpu_info(b"K230");
+    let k = Kendryte::new(mock, OUT_EP, IN_EP).with_counts(counts.clone());
+    k.cpu_info_raw().unwrap();
+    assert_eq!((counts.sent(), counts.received()), (1000, 16 + 4));
+}
+
+#[test]
+fn mismatches_and_input_errors_are_told_apart() {
+    let k = kendryte();
+    k.load(SRAM_RUN_BASE, &[1, 2, 3][..]).unwrap();
+    let e = k.verify(SRAM_RUN_BASE, &[1, 2, 4][..]).unwrap_err();
+    let mismatch = e.get_ref().unwrap().downcast_ref::<Mismatch>().unwrap();
+    assert_eq!(
+        (mismatch.offset, mismatch.expected, mismatch.actual),
+        (2, 4, 3)
+    );
+
+    let failing = std::io::Read::chain(&[0; 600][..], FailingReader);
+    let e = k.load(SRAM_RUN_BASE, failing).unwrap_err();
+    let input = e.get_ref().unwrap().downcast_ref::<InputError>().unwrap();
+    assert_eq!(input.offset, 600);
+}
+
+struct FailingReader;
+
+impl std::io::Read for FailingReader {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other("disk on fire"))
+    }
+}
+
+#[test]
+fn reads_past_the_address_space_are_refused() {
+    let k = kendryte();
+    let mut buf = [0; 0x200];
+    let e = k.read(0xffff_ff00, &mut buf).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert!(k.transport().calls().is_empty());
+    k.read(0xffff_ff00, &mut buf[..0x100]).unwrap();
+
+    let e = k.verify_read_back(0xffff_ff00, &buf[..]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn cpu_info_from_control_in() {
+    let mock = MockTransport::new().with_cpu_info(b"K230");
+    let k = Kendryte::new(mock, OUT_EP, IN_EP);
+    assert_eq!(k.cpu_info().unwrap(), "K230");
+}
+
+#[test]
+fn cpu_info_drops_padding_and_bad_utf8() {
+    let mock = MockTransport::new().with_cpu_info(b"K230\xff\x01\0\0\0");
+    let k = Kendryte::new(mock, OUT_EP, IN_EP);
+    assert_eq!(k.cpu_info().unwrap(), "K230\u{fffd}\u{1}");
+    assert_eq!(k.cpu_info_raw().unwrap(), b"K230\xff\x01\0\0\0");
+}
+
+#[test]
+fn cpu_info_decodes_only_what_was_sent() {
+    let mock = MockTransport::new().with_cpu_info(b"K2");
+    let k = Kendryte::new(mock, OUT_EP, IN_EP);
+    assert_eq!(k.cpu_info_raw().unwrap(), b"K2");
+
+    let k = Kendryte::new(MockTransport::new(), OUT_EP, IN_EP);
+    let e = k.cpu_info().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    assert!(e.to_string().contains("no CPU info"), "{e}");
+}
+
+#[test]
+fn cpu_info_splits_text_from_binary() {
+    let info = Chip::K230
+        .decode_cpu_info(b"K230 \x01\x00\x02\0\0\0")
+        .unwrap();
+    assert_eq!(info.text, "K230");
+    assert_eq!(info.data, b"\x01\x00\x02");
+
+    let info = Chip::K230.decode_cpu_info(b"K230\0\0").unwrap();
+    assert_eq!(info.data, b"");
+    assert_eq!(Chip::K230.decode_cpu_info(b"U-Boot"), None);
+}
+
+#[test]
+fn load_can_be_cancelled_between_chunks() {
+    let k = kendryte().with_chunk_size(256);
+    let cancel = AtomicBool::new(false);
+    let mut chunks = 0;
+    let e = k
+        .load_cancellable(SRAM_RUN_BASE, &[0; 1024][..], None, &cancel, |_| {
+            chunks += 1;
+            cancel.store(chunks == 2, Ordering::SeqCst);
+        })
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Interrupted);
+    let cancelled = e.get_ref().unwrap().downcast_ref::<Cancelled>().unwrap();
+    assert_eq!(cancelled.sent, 512);
+    assert_eq!(bulk_out_lengths(&k.transport().calls()), [256, 256]);
+
+    cancel.store(false, Ordering::SeqCst);
+    assert_eq!(k.load(SRAM_RUN_BASE, &[0; 1024][..]).unwrap(), 1024);
+}
+
+#[test]
+fn load_stops_when_flagged() {
+    let stop = Arc::new(AtomicBool::new(true));
+    let k = kendryte().with_stop_flag(stop);
+    let e = k.load(SRAM_RUN_BASE, &[0; 1024][..]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Interrupted);
+    assert!(bulk_out_lengths(&k.transport().calls()).is_empty());
+}
+
+#[test]
+fn best_effort_load_skips_failed_chunk() {
+    let bad = SRAM_RUN_BASE + 20;
+    let mock = MockTransport::new().with_bad_address(bad);
+    let k = Kendryte::new(mock, OUT_EP, IN_EP)
+        .with_chunk_size(16)
+        .with_best_effort(true);
+    let data: Vec<u8> = (1..=64).collect();
+
+    assert_eq!(k.load(SRAM_RUN_BASE, &data[..]).unwrap(), 64);
+    assert_eq!(
+        k.take_failed(),
+        vec![SRAM_RUN_BASE + 16..SRAM_RUN_BASE + 32]
+    );
+    assert!(k.take_failed().is_empty());
+
+    let mock = k.transport();
+    assert_eq!(mock.memory(SRAM_RUN_BASE, 16), data[..16]);
+    assert_eq!(mock.memory(SRAM_RUN_BASE + 16, 16), [0; 16]);
+    assert_eq!(mock.memory(SRAM_RUN_BASE + 32, 32), data[32..]);
+}
+
+#[test]
+fn failed_chunk_tells_its_offset() {
+    let mock = MockTransport::new().with_bad_address(SRAM_RUN_BASE + 16);
+    let k = Kendryte::new(mock, OUT_EP, IN_EP).with_chunk_size(16);
+    let data: Vec<u8> = (1..=64).collect();
+
+    let e = k.load(SRAM_RUN_BASE, &data[..]).unwrap_err();
+    let chunk = e.get_ref().unwrap().downcast_ref::<ChunkError>().unwrap();
+    assert_eq!(chunk.offset, 16);
+    assert_eq!(chunk.addr, SRAM_RUN_BASE as u64 + 16);
+
+    let e = Error::transfer("Failed to load")(e);
+    assert_eq!(e.kind(), "transfer");
+    assert_eq!(e.offset(), Some(16));
+}
+
+#[test]
+fn verify_chunks_reads_each_chunk_back() {
+    let k = kendryte().with_chunk_size(16).with_verify_chunks(true);
+    let data: Vec<u8> = (1..=40).collect();
+    k.load(SRAM_RUN_BASE, &data[..]).unwrap();
+
+    let calls = k.transport().calls();
+    let reads: Vec<usize> = calls
+        .iter()
+        .filter_map(|c| match c {
+            Call::BulkIn { ep: IN_EP, length } => Some(*length),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(reads, [16, 16, 8]);
+    assert_eq!(bulk_out_lengths(&calls), [16, 16, 8]);
+    assert_eq!(k.transport().memory(SRAM_RUN_BASE, 40), data);
+}
+
+#[test]
+fn timeout_grows_with_chunk_size() {
+    let small = timeout_for(512, Speed::High);
+    assert!(small < TRANSFER_TIMEOUT, "{small:?}");
+    assert!(timeout_for(512, Speed::Full) > small);
+    assert!(timeout_for(64 << 20, Speed::High) > Duration::from_secs(60));
+}
+
+#[test]
+fn self_test_reads_back_what_it_wrote() {
+    let k = kendryte().with_chunk_size(16);
+    k.self_test(SRAM_RUN_BASE, 40).unwrap();
+
+    let calls = k.transport().calls();
+    assert_eq!(bulk_out_lengths(&calls), [16, 16, 8]);
+    let written = k.transport().memory(SRAM_RUN_BASE, 40);
+    assert!(written.iter().any(|&b| b != written[0]));
+}